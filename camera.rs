@@ -0,0 +1,439 @@
+use cgmath::*;
+use winit::event::*;
+
+const SAFE_PITCH: f32 = std::f32::consts::FRAC_PI_2 - 0.01;
+const MIN_DISTANCE: f32 = 0.1;
+// orbit distance is divided by this for every scroll line
+const ZOOM_STEP: f32 = 1.1;
+// trackpads report pixels, this many make up one wheel line
+const PIXELS_PER_LINE: f32 = 40.0;
+
+pub struct Camera {
+    pub position: Point3<f32>,
+    pub direction: Point3<f32>,
+    pub up: Vector3<f32>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CameraMode {
+    // rotate around and zoom towards `Camera::direction`
+    Orbit,
+    // move freely, `Camera::direction` stays in front of the camera
+    Fly,
+}
+
+// Keyboard/mouse state for moving a Camera. Held keys are kept as amounts
+// in 0..1 so that several can be held together, and all motion is scaled
+// by the frame time in update_camera.
+pub struct CameraController {
+    pub mode: CameraMode,
+    // world units per second
+    pub speed: f32,
+    // radians per second for held orbit keys
+    pub rotate_speed: f32,
+    // radians per pixel of mouse motion
+    pub sensitivity: f32,
+    // vertical field of view, used to make drag panning follow the cursor
+    pub fovy: Rad<f32>,
+    forward: f32,
+    backward: f32,
+    left: f32,
+    right: f32,
+    up: f32,
+    down: f32,
+    pan_left: f32,
+    pan_right: f32,
+    pan_up: f32,
+    pan_down: f32,
+    looking: bool,
+    rotating: bool,
+    panning: bool,
+    viewport: (f32, f32),
+    cursor: Option<(f32, f32)>,
+    // accumulated camera-space arcball rotation and drag pan in pixels
+    arcball: Quaternion<f32>,
+    drag_pan: Vector2<f32>,
+    rotate_horizontal: f32,
+    rotate_vertical: f32,
+    scroll: f32,
+}
+
+impl CameraController {
+    pub fn new(mode: CameraMode, speed: f32, rotate_speed: f32, sensitivity: f32) -> Self {
+        Self {
+            mode,
+            speed,
+            rotate_speed,
+            sensitivity,
+            fovy: Rad(2.0 * std::f32::consts::PI / 5.0),
+            forward: 0.0,
+            backward: 0.0,
+            left: 0.0,
+            right: 0.0,
+            up: 0.0,
+            down: 0.0,
+            pan_left: 0.0,
+            pan_right: 0.0,
+            pan_up: 0.0,
+            pan_down: 0.0,
+            looking: false,
+            rotating: false,
+            panning: false,
+            viewport: (1.0, 1.0),
+            cursor: None,
+            arcball: Quaternion::one(),
+            drag_pan: Vector2::zero(),
+            rotate_horizontal: 0.0,
+            rotate_vertical: 0.0,
+            scroll: 0.0,
+        }
+    }
+
+    // Orbit: W/S and A/D orbit, arrows pan, Space/LShift zoom.
+    // Fly: W/S and A/D move, Space/LShift rise and sink.
+    // Tab switches between the two.
+    pub fn process_keyboard(&mut self, key: VirtualKeyCode, state: ElementState) -> bool {
+        let amount = if state == ElementState::Pressed { 1.0 } else { 0.0 };
+        match key {
+            VirtualKeyCode::W => self.up = amount,
+            VirtualKeyCode::S => self.down = amount,
+            VirtualKeyCode::A => self.left = amount,
+            VirtualKeyCode::D => self.right = amount,
+            VirtualKeyCode::Space => self.forward = amount,
+            VirtualKeyCode::LShift => self.backward = amount,
+            VirtualKeyCode::Up => self.pan_up = amount,
+            VirtualKeyCode::Down => self.pan_down = amount,
+            VirtualKeyCode::Left => self.pan_left = amount,
+            VirtualKeyCode::Right => self.pan_right = amount,
+            VirtualKeyCode::Tab => {
+                if state == ElementState::Pressed {
+                    self.mode = match self.mode {
+                        CameraMode::Orbit => CameraMode::Fly,
+                        CameraMode::Fly => CameraMode::Orbit,
+                    };
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn set_viewport(&mut self, width: u32, height: u32) {
+        self.viewport = (width.max(1) as f32, height.max(1) as f32);
+    }
+
+    // Left drag rotates around the target (orbit mode), middle drag pans and
+    // the right button enables mouse look (fly mode).
+    pub fn process_mouse_button(&mut self, button: MouseButton, state: ElementState) -> bool {
+        let pressed = state == ElementState::Pressed;
+        match button {
+            MouseButton::Left => self.rotating = pressed,
+            MouseButton::Middle => self.panning = pressed,
+            MouseButton::Right => self.looking = pressed,
+            _ => return false,
+        }
+        true
+    }
+
+    // cursor position in physical pixels, from WindowEvent::CursorMoved
+    pub fn process_cursor_moved(&mut self, x: f64, y: f64) {
+        let current = (x as f32, y as f32);
+        if let Some(previous) = self.cursor.replace(current) {
+            if self.rotating && self.mode == CameraMode::Orbit {
+                let rotation = Quaternion::from_arc(self.arcball_vector(previous), self.arcball_vector(current), None);
+                self.arcball = rotation * self.arcball;
+            }
+            if self.panning {
+                self.drag_pan += Vector2::new(current.0 - previous.0, current.1 - previous.1);
+            }
+        }
+    }
+
+    pub fn process_cursor_left(&mut self) {
+        self.cursor = None;
+    }
+
+    // raw pointer motion in pixels, e.g. from DeviceEvent::MouseMotion
+    pub fn process_mouse_motion(&mut self, dx: f64, dy: f64) {
+        if self.looking {
+            self.rotate_horizontal += dx as f32;
+            self.rotate_vertical += dy as f32;
+        }
+    }
+
+    pub fn process_scroll(&mut self, delta: &MouseScrollDelta) {
+        self.scroll += match delta {
+            MouseScrollDelta::LineDelta(_, y) => *y,
+            MouseScrollDelta::PixelDelta(position) => position.y as f32 / PIXELS_PER_LINE,
+        };
+    }
+
+    // Maps a cursor position onto Shoemake's arcball: a unit sphere filling
+    // the smaller viewport dimension, in camera space (x right, y up, z back).
+    fn arcball_vector(&self, (x, y): (f32, f32)) -> Vector3<f32> {
+        let (width, height) = self.viewport;
+        let radius = width.min(height) * 0.5;
+        let p = Vector2::new((x - width * 0.5) / radius, (height * 0.5 - y) / radius);
+        let length2 = p.magnitude2();
+        if length2 <= 1.0 {
+            Vector3::new(p.x, p.y, (1.0 - length2).sqrt())
+        } else {
+            p.normalize().extend(0.0)
+        }
+    }
+
+    pub fn update_camera(&mut self, camera: &mut Camera, dt: std::time::Duration) {
+        let dt = dt.as_secs_f32();
+        match self.mode {
+            CameraMode::Orbit => self.update_orbit(camera, dt),
+            CameraMode::Fly => self.update_fly(camera, dt),
+        }
+        self.rotate_horizontal = 0.0;
+        self.rotate_vertical = 0.0;
+        self.scroll = 0.0;
+        self.arcball = Quaternion::one();
+        self.drag_pan = Vector2::zero();
+    }
+
+    fn update_orbit(&mut self, camera: &mut Camera, dt: f32) {
+        let mut offset = camera.position - camera.direction;
+        let mut distance = offset.magnitude();
+        let (right, up) = screen_axes(-offset, camera.up);
+
+        // the arcball turns the scene, so the camera turns the other way
+        if self.arcball != Quaternion::one() {
+            let back = offset / distance;
+            let basis = Matrix3::from_cols(right, up, back);
+            let world = Quaternion::from(basis) * self.arcball.invert() * Quaternion::from(basis.transpose());
+            offset = world.rotate_vector(offset);
+            camera.up = world.rotate_vector(camera.up);
+        }
+
+        let yaw = (self.left - self.right) * self.rotate_speed * dt - self.rotate_horizontal * self.sensitivity;
+        offset = Quaternion::from_axis_angle(camera.up, Rad(-yaw)).rotate_vector(offset);
+        let pitch = (self.up - self.down) * self.rotate_speed * dt + self.rotate_vertical * self.sensitivity;
+        if pitch != 0.0 {
+            // keep clear of the poles, look_at breaks down when looking along up
+            let elevation = (offset.normalize().dot(camera.up)).clamp(-1.0, 1.0).asin();
+            let pitch = (elevation + pitch).clamp(-SAFE_PITCH, SAFE_PITCH) - elevation;
+            let (right, _) = screen_axes(-offset, camera.up);
+            offset = Quaternion::from_axis_angle(right, Rad(-pitch)).rotate_vector(offset);
+        }
+
+        distance -= (self.forward - self.backward) * self.speed * dt;
+        distance /= ZOOM_STEP.powf(self.scroll);
+        distance = distance.max(MIN_DISTANCE);
+
+        // pan moves the target and the camera together, drags follow the cursor
+        let (right, up) = screen_axes(-offset, camera.up);
+        let world_per_pixel = 2.0 * distance * (self.fovy / 2.0).tan() / self.viewport.1;
+        let pan = (right * (self.pan_right - self.pan_left) + up * (self.pan_up - self.pan_down)) * self.speed * dt
+            + (up * self.drag_pan.y - right * self.drag_pan.x) * world_per_pixel;
+        camera.direction += pan;
+        camera.position = camera.direction + offset.normalize() * distance;
+    }
+
+    fn update_fly(&mut self, camera: &mut Camera, dt: f32) {
+        // free flight keeps the horizon level
+        camera.up = Vector3::unit_y();
+        let (mut yaw, mut pitch, distance) = spherical(camera.direction - camera.position);
+        yaw += self.rotate_horizontal * self.sensitivity;
+        pitch -= self.rotate_vertical * self.sensitivity;
+        pitch = pitch.clamp(-SAFE_PITCH, SAFE_PITCH);
+        let forward = from_spherical(yaw, pitch);
+        let (right, _) = screen_axes(forward, camera.up);
+
+        let velocity = forward * (self.up - self.down) + right * (self.right - self.left)
+            + camera.up * (self.forward - self.backward);
+        let world_per_pixel = 2.0 * distance * (self.fovy / 2.0).tan() / self.viewport.1;
+        let pan = (camera.up * self.drag_pan.y - right * self.drag_pan.x) * world_per_pixel;
+        camera.position += velocity * self.speed * dt + forward * self.scroll + pan;
+        // keep the target at the same distance so switching back to orbit feels natural
+        camera.direction = camera.position + forward * distance.max(MIN_DISTANCE);
+    }
+}
+
+// (yaw, pitch, length) of a y-up vector
+fn spherical(v: Vector3<f32>) -> (f32, f32, f32) {
+    let length = v.magnitude();
+    if length == 0.0 {
+        return (0.0, 0.0, 0.0);
+    }
+    (v.z.atan2(v.x), (v.y / length).clamp(-1.0, 1.0).asin(), length)
+}
+
+fn from_spherical(yaw: f32, pitch: f32) -> Vector3<f32> {
+    Vector3::new(pitch.cos() * yaw.cos(), pitch.sin(), pitch.cos() * yaw.sin())
+}
+
+// right and up directions on screen when looking along `forward`
+fn screen_axes(forward: Vector3<f32>, up: Vector3<f32>) -> (Vector3<f32>, Vector3<f32>) {
+    let right = forward.cross(up).normalize();
+    (right, right.cross(forward).normalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn camera() -> Camera {
+        Camera {
+            position: (0.0, 0.0, -10.0).into(),
+            direction: (0.0, 0.0, 0.0).into(),
+            up: Vector3::unit_y(),
+        }
+    }
+
+    fn controller(mode: CameraMode) -> CameraController {
+        CameraController::new(mode, 4.0, std::f32::consts::FRAC_PI_2, 0.01)
+    }
+
+    fn assert_near(a: Point3<f32>, b: Point3<f32>) {
+        assert!((a - b).magnitude() < 1e-3, "{:?} != {:?}", a, b);
+    }
+
+    #[test]
+    fn orbit_keeps_distance_and_target() {
+        let mut camera = camera();
+        let mut controller = controller(CameraMode::Orbit);
+        controller.process_keyboard(VirtualKeyCode::A, ElementState::Pressed);
+        controller.update_camera(&mut camera, Duration::from_secs(1));
+        // a quarter turn around y, to the camera's left (+x when looking at +z)
+        assert_near(camera.position, (10.0, 0.0, 0.0).into());
+        assert_near(camera.direction, (0.0, 0.0, 0.0).into());
+    }
+
+    #[test]
+    fn motion_scales_with_frame_time() {
+        let (mut a, mut b) = (camera(), camera());
+        let mut controller = controller(CameraMode::Orbit);
+        controller.process_keyboard(VirtualKeyCode::Space, ElementState::Pressed);
+        controller.update_camera(&mut a, Duration::from_millis(500));
+        for _ in 0..5 {
+            controller.update_camera(&mut b, Duration::from_millis(100));
+        }
+        assert_near(a.position, (0.0, 0.0, -8.0).into());
+        assert_near(a.position, b.position);
+
+        // releasing the key stops the motion
+        controller.process_keyboard(VirtualKeyCode::Space, ElementState::Released);
+        controller.update_camera(&mut a, Duration::from_secs(1));
+        assert_near(a.position, (0.0, 0.0, -8.0).into());
+    }
+
+    #[test]
+    fn held_keys_combine() {
+        let mut camera = camera();
+        let mut controller = controller(CameraMode::Orbit);
+        controller.process_keyboard(VirtualKeyCode::Right, ElementState::Pressed);
+        controller.process_keyboard(VirtualKeyCode::Up, ElementState::Pressed);
+        controller.update_camera(&mut camera, Duration::from_secs(1));
+        // right is -x when looking at +z
+        assert_near(camera.direction, (-4.0, 4.0, 0.0).into());
+        assert_near(camera.position, (-4.0, 4.0, -10.0).into());
+    }
+
+    #[test]
+    fn orbit_pitch_is_clamped_and_zoom_has_a_floor() {
+        let mut camera = camera();
+        let mut controller = controller(CameraMode::Orbit);
+        controller.process_keyboard(VirtualKeyCode::W, ElementState::Pressed);
+        controller.update_camera(&mut camera, Duration::from_secs(5));
+        let offset = camera.position - camera.direction;
+        assert!(offset.y < 10.0 && offset.y > 9.9);
+        assert!(offset.x.abs() + offset.z.abs() > 0.0);
+
+        controller.process_scroll(&MouseScrollDelta::LineDelta(0.0, 100.0));
+        controller.update_camera(&mut camera, Duration::ZERO);
+        assert!(((camera.position - camera.direction).magnitude() - MIN_DISTANCE).abs() < 1e-4);
+    }
+
+    #[test]
+    fn fly_moves_along_view_and_looks_with_mouse() {
+        let mut camera = camera();
+        let mut controller = controller(CameraMode::Fly);
+        controller.process_keyboard(VirtualKeyCode::W, ElementState::Pressed);
+        controller.update_camera(&mut camera, Duration::from_secs(1));
+        assert_near(camera.position, (0.0, 0.0, -6.0).into());
+        assert_near(camera.direction, (0.0, 0.0, 4.0).into());
+        controller.process_keyboard(VirtualKeyCode::W, ElementState::Released);
+
+        // motion is ignored unless the right button is held
+        controller.process_mouse_motion(100.0, 0.0);
+        controller.update_camera(&mut camera, Duration::from_secs(1));
+        assert_near(camera.direction, (0.0, 0.0, 4.0).into());
+
+        controller.process_mouse_button(MouseButton::Right, ElementState::Pressed);
+        controller.process_mouse_motion(std::f64::consts::FRAC_PI_2 * 100.0, 0.0);
+        controller.update_camera(&mut camera, Duration::from_secs(1));
+        // turned right, which is -x
+        assert_near(camera.position, (0.0, 0.0, -6.0).into());
+        assert_near(camera.direction, (-10.0, 0.0, -6.0).into());
+    }
+
+    #[test]
+    fn arcball_drag_rotates_around_target() {
+        let mut camera = camera();
+        let mut controller = controller(CameraMode::Orbit);
+        controller.set_viewport(200, 200);
+        controller.process_cursor_moved(100.0, 100.0);
+        controller.process_mouse_button(MouseButton::Left, ElementState::Pressed);
+        // from the center of the ball to its right edge is a quarter turn
+        controller.process_cursor_moved(200.0, 100.0);
+        controller.update_camera(&mut camera, Duration::ZERO);
+        // dragging right swings the camera to its left (+x here)
+        assert_near(camera.position, (10.0, 0.0, 0.0).into());
+        assert_near(camera.direction, (0.0, 0.0, 0.0).into());
+        assert!((camera.up - Vector3::unit_y()).magnitude() < 1e-4);
+
+        // dragging down to the bottom edge rolls the camera over the top
+        controller.process_cursor_moved(100.0, 100.0);
+        controller.process_cursor_moved(100.0, 200.0);
+        controller.update_camera(&mut camera, Duration::ZERO);
+        assert_near(camera.position, (0.0, 10.0, 0.0).into());
+
+        // cursor motion without a button held does nothing
+        controller.process_mouse_button(MouseButton::Left, ElementState::Released);
+        controller.process_cursor_moved(0.0, 0.0);
+        controller.update_camera(&mut camera, Duration::ZERO);
+        assert_near(camera.position, (0.0, 10.0, 0.0).into());
+    }
+
+    #[test]
+    fn middle_drag_pans_with_the_cursor() {
+        let mut camera = camera();
+        let mut controller = controller(CameraMode::Orbit);
+        controller.fovy = Rad(std::f32::consts::FRAC_PI_2);
+        controller.set_viewport(200, 200);
+        controller.process_mouse_button(MouseButton::Middle, ElementState::Pressed);
+        controller.process_cursor_moved(100.0, 100.0);
+        controller.process_cursor_moved(150.0, 100.0);
+        controller.update_camera(&mut camera, Duration::ZERO);
+        // 90 degrees at distance 10 shows 20 units over 200 pixels; grabbing
+        // the scene and moving it right moves the camera left, +x
+        assert_near(camera.direction, (5.0, 0.0, 0.0).into());
+        assert_near(camera.position, (5.0, 0.0, -10.0).into());
+    }
+
+    #[test]
+    fn trackpad_pixels_zoom_in_fractions_of_a_line() {
+        let (mut a, mut b) = (camera(), camera());
+        let mut controller = controller(CameraMode::Orbit);
+        controller.process_scroll(&MouseScrollDelta::PixelDelta(winit::dpi::PhysicalPosition::new(0.0, 20.0)));
+        controller.update_camera(&mut a, Duration::ZERO);
+        controller.process_scroll(&MouseScrollDelta::LineDelta(0.0, 0.5));
+        controller.update_camera(&mut b, Duration::ZERO);
+        assert_near(a.position, b.position);
+        assert!(a.position.z > -10.0);
+    }
+
+    #[test]
+    fn tab_toggles_mode() {
+        let mut controller = controller(CameraMode::Orbit);
+        assert!(controller.process_keyboard(VirtualKeyCode::Tab, ElementState::Pressed));
+        controller.process_keyboard(VirtualKeyCode::Tab, ElementState::Released);
+        assert_eq!(controller.mode, CameraMode::Fly);
+        assert!(!controller.process_keyboard(VirtualKeyCode::Q, ElementState::Pressed));
+    }
+}
@@ -1,132 +1,72 @@
 use std:: {iter};
 use instancing::Instance;
 use model::Vertex;
-use wgpu::util::DeviceExt;
 use cgmath::*;
 use winit::{
     event::*,
     window::Window,
 };
 // use fs_extra;
-use bytemuck:: {Pod, Zeroable};
 
 // use crate::transforms;
-use crate::{transforms, instancing, model::{self, DrawModel}, resources};
+use crate::{camera::{Camera, CameraController, CameraMode}, environment::{Environment, EnvironmentSettings}, lights, pipeline::{self, PipelineCache, PipelineKey}, render_targets::RenderTargets, shadows::{ShadowMaps, ShadowSettings}, transforms, instancing, model::{self, DrawModel}, resources, texture};
 
 
-const IS_PERSPECTIVE:bool = true;
 const ANIMATION_SPEED:f32 = 0.002;
 
-#[repr(C)]
-#[derive(Copy, Clone, Debug, Pod, Zeroable)]
-pub struct Light {
-    specular_color: [f32; 4],
-    ambient: f32,
-    diffuse: f32,
-    specular_intensity: f32,
-    specular_shininess: f32,
-}
-
-pub fn light(specular_color: [f32; 3], ambient: f32, diffuse: f32, specular_intensity: f32, specular_shininess: f32) -> Light {
-    Light {
-        specular_color: [specular_color[0], specular_color[1], specular_color[2], 1.0],
-        ambient,
-        diffuse,
-        specular_intensity,
-        specular_shininess,
-    }
-}
-
-pub struct Camera {
-    pub position: Point3<f32>,
-    pub direction: Point3<f32>,
-    pub up: Vector3<f32>,
-}
-
 pub(crate) struct State {
     pub init: transforms::InitWgpu,
-    pipeline: wgpu::RenderPipeline,
-    light_render_pipeline: wgpu::RenderPipeline,
-    instances: Vec<instancing::Instance>,
+    pipelines: PipelineCache,
+    depth_mode: transforms::DepthMode,
+    targets: RenderTargets,
+    instances: instancing::InstanceSet,
     obj_model: model::Model,
+    // group 1 of the model pipelines, kept for loading other models
+    material_layout: wgpu::BindGroupLayout,
     vertex_uniform_buffer: wgpu::Buffer,
     fragment_uniform_buffer: wgpu::Buffer,
-    instance_buffer: wgpu::Buffer,
     uniform_bind_group:wgpu::BindGroup,
     model_mat: Matrix4<f32>,
     view_mat: Matrix4<f32>,
     project_mat: Matrix4<f32>,
-    direct: String,
+    projection: transforms::Projection,
+    // orbit distance when the projection was set, see view_projection
+    projection_distance: f32,
+    // far plane to return to when leaving an infinite reverse-Z projection
+    finite_far: f32,
     camera: Camera,
-    light_instance: Point3<f32>,
+    camera_controller: CameraController,
+    // total time passed to the last update, frame times are derived from it
+    elapsed: std::time::Duration,
+    lights: Vec<lights::Light>,
+    // scales the image-based lighting
+    ambient: [f32; 3],
+    light_uniform_buffer: wgpu::Buffer,
+    shadows: ShadowMaps,
+    environment: Environment,
 }
 
-fn create_render_pipeline(
-    device: &wgpu::Device,
-    layout: &wgpu::PipelineLayout,
-    color_format: wgpu::TextureFormat,
-    depth_format: Option<wgpu::TextureFormat>,
-    vertex_layouts: &[wgpu::VertexBufferLayout],
-    shader: wgpu::ShaderModuleDescriptor,
-) -> wgpu::RenderPipeline {
-    let shader = device.create_shader_module(shader);
-
-    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-        label: Some("Render Pipeline"),
-        layout: Some(layout),
-        vertex: wgpu::VertexState {
-            module: &shader,
-            entry_point: "vs_main",
-            buffers: vertex_layouts,
-        },
-        fragment: Some(wgpu::FragmentState {
-            module: &shader,
-            entry_point: "fs_main",
-            targets: &[Some(wgpu::ColorTargetState {
-                format: color_format,
-                blend: Some(wgpu::BlendState {
-                    alpha: wgpu::BlendComponent::REPLACE,
-                    color: wgpu::BlendComponent::REPLACE,
-                }),
-                write_mask: wgpu::ColorWrites::ALL,
-            })],
-        }),
-        primitive: wgpu::PrimitiveState {
-            topology: wgpu::PrimitiveTopology::TriangleList,
-            strip_index_format: None,
-            front_face: wgpu::FrontFace::Ccw,
-            cull_mode: Some(wgpu::Face::Back),
-            // Setting this to anything other than Fill requires Features::NON_FILL_POLYGON_MODE
-            polygon_mode: wgpu::PolygonMode::Fill,
-            // Requires Features::DEPTH_CLIP_CONTROL
-            unclipped_depth: false,
-            // Requires Features::CONSERVATIVE_RASTERIZATION
-            conservative: false,
-        },
-        depth_stencil: depth_format.map(|format| wgpu::DepthStencilState {
-            format,
-            depth_write_enabled: true,
-            depth_compare: wgpu::CompareFunction::Less,
-            stencil: wgpu::StencilState::default(),
-            bias: wgpu::DepthBiasState::default(),
-        }),
-        multisample: wgpu::MultisampleState {
-            count: 1,
-            mask: !0,
-            alpha_to_coverage_enabled: false,
-        },
-        // If the pipeline will be used with a multiview render pass, this
-        // indicates how many array layers the attachments will have.
-        multiview: None,
-    })
-}
+// ids the scene's shaders and layouts are registered with in the PipelineCache
+const MODEL_SHADER: &str = "lightning";
+const LIGHT_SHADER: &str = "light";
+const SKYBOX_SHADER: &str = "skybox";
+const MODEL_LAYOUT: &str = "model";
+const LIGHT_LAYOUT: &str = "light";
+const SKYBOX_LAYOUT: &str = "skybox";
 
 impl State {
-    pub async fn new(window: &Window) -> Self {        
-        let init =  transforms::InitWgpu::init_wgpu(window).await;
+    pub async fn new(window: &Window, init_config: &transforms::InitConfig) -> anyhow::Result<Self> {        
+        let init =  transforms::InitWgpu::init_wgpu(window, init_config).await?;
+        Self::from_init(init).await
+    }
 
-        
+    // Draws into an offscreen texture of the given size; no window required.
+    pub async fn new_headless(width: u32, height: u32, init_config: &transforms::InitConfig) -> anyhow::Result<Self> {
+        let init = transforms::InitWgpu::init_headless(width, height, init_config).await?;
+        Self::from_init(init).await
+    }
 
+    async fn from_init(init: transforms::InitWgpu) -> anyhow::Result<Self> {
         let texture_bind_group_layout =
             init.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
                 entries: &[
@@ -162,13 +102,72 @@ impl State {
                         ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
                         count: None,
                     },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 4,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Buffer {
+                            ty: wgpu::BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    // metallic-roughness, occlusion and emissive maps
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 5,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 6,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 7,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 8,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 9,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: wgpu::TextureViewDimension::D2,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 10,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
                 ],
                 label: Some("texture_bind_group_layout"),
             });
                 
         let obj_model =
             resources::load_model("cube.obj", &init.device, &init.queue, &texture_bind_group_layout)
-                .await.unwrap();
+                .await?;
 
         // uniform data
         let position = (0.0, 5.0, -10.0).into();
@@ -179,22 +178,20 @@ impl State {
             direction,
             up
         };
-            // println!("{},{}",vertex_data.len(), indices.len());
-        let light = light(
-            // camera.position.into(),
-            // [0.0, 1.0, 0.0],
-            [1.0, 1.0, 1.0],
-            0.3,
-            0.3,
-            0.3,
-            32.0,
-        );
+        // one white light where the camera starts, and a sun for shadows
+        let lights = vec![
+            lights::Light::point(camera.position, 100.0, [1.0, 1.0, 1.0], 40.0),
+            lights::Light::directional((-0.4, -1.0, 0.3).into(), [1.0, 0.95, 0.85], 0.6),
+        ];
+        let ambient = [1.0, 1.0, 1.0];
+
+        let mut camera_controller = CameraController::new(CameraMode::Orbit, 4.0, std::f32::consts::FRAC_PI_2, 0.004);
+        camera_controller.set_viewport(init.config.width, init.config.height);
 
-        
         let model_mat = transforms::create_transforms([0.0,0.0,0.0], [0.0,0.0,0.0], [1.0,1.0,1.0]);
-        let (view_mat, project_mat, _) = 
-            transforms::create_view_projection(camera.position, camera.direction, camera.up, 
-            init.config.width as f32 / init.config.height as f32, IS_PERSPECTIVE);
+        let projection = transforms::Projection::default();
+        let view_mat = transforms::create_view(camera.position, camera.direction, camera.up);
+        let project_mat = projection.matrix(init.config.width as f32 / init.config.height as f32);
 
         let vertex_uniform_buffer = init.device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("Vertex Buffer"),
@@ -204,23 +201,19 @@ impl State {
         });
         let fragment_uniform_buffer = init.device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("Fragment Uniform Buffer"),
-            size: 32,
+            size: 16,
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
         let light_uniform_buffer = init.device.create_buffer(&wgpu::BufferDescriptor {
             label: Some("Light Uniform Buffer"),
-            size: 32,
+            size: std::mem::size_of::<lights::LightsUniform>() as wgpu::BufferAddress,
             usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
-        let light_position: [f32; 3] = [2.0, 2.0, 2.0];
         let eye_position: [f32; 3] = [camera.position.x, camera.position.y, camera.position.z];
-        // light position
-        init.queue.write_buffer(&fragment_uniform_buffer, 0, bytemuck::cast_slice(light_position.as_ref()));
         // eye position
-        init.queue.write_buffer(&fragment_uniform_buffer, 16, bytemuck::cast_slice(eye_position.as_ref()));
-        init.queue.write_buffer(&light_uniform_buffer, 0, bytemuck::cast_slice(&[light]));
+        init.queue.write_buffer(&fragment_uniform_buffer, 0, bytemuck::cast_slice(eye_position.as_ref()));
 
         let uniform_bind_group_layout = init.device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor{
             entries: &[wgpu::BindGroupLayoutEntry {
@@ -243,9 +236,10 @@ impl State {
                 },
                 count: None,
             },
+            // the light list, light.wgsl places its markers with it
             wgpu::BindGroupLayoutEntry {
                 binding: 2,
-                visibility: wgpu::ShaderStages::FRAGMENT,
+                visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
                 ty: wgpu::BindingType::Buffer {
                     ty: wgpu::BufferBindingType::Uniform,
                     has_dynamic_offset: false,
@@ -273,72 +267,78 @@ impl State {
             label: Some("Uniform Bind Group"),
         });
 
-        let pipeline = {
-            let pipeline_layout = init.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("Render Pipeline Layout"),
-                bind_group_layouts: &[&uniform_bind_group_layout, &texture_bind_group_layout],
-                push_constant_ranges: &[],
-            });
-            let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("Shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("lightning.wgsl").into()),
-            };
-            create_render_pipeline(&init.device, &pipeline_layout, init.config.format, Some(wgpu::TextureFormat::Depth24Plus), &[model::ModelVertex::desc(), instancing::InstanceRaw::desc()], shader)
-        };
-        
-        let light_render_pipeline = {
-            let layout = init.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                label: Some("light pipeline"),
-                bind_group_layouts: &[&uniform_bind_group_layout],
-                push_constant_ranges: &[],
-            });
-            let shader = wgpu::ShaderModuleDescriptor {
-                label: Some("light shader"),
-                source: wgpu::ShaderSource::Wgsl(include_str!("light.wgsl").into()),
-            };
-            create_render_pipeline(&init.device, &layout, init.config.format, Some(wgpu::TextureFormat::Depth24Plus), &[model::ModelVertex::desc()], shader)
-        };
+        let shadows = ShadowMaps::new(&init.device, ShadowSettings::default());
+        let environment = Environment::new(&init.device, &init.queue, EnvironmentSettings::default());
 
-        let instances = instancing::craete_instances();
-        let instance_data = instances.iter().map(instancing::Instance::to_raw).collect::<Vec<_>>();
-        let instance_buffer = init.device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Instance Buffer"),
-                contents: bytemuck::cast_slice(&instance_data),
-                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
-            }
-        );
-        Self {
+        let mut pipelines = PipelineCache::new();
+        pipelines.add_shader(&init.device, MODEL_SHADER, include_str!("lightning.wgsl"));
+        pipelines.add_shader(&init.device, LIGHT_SHADER, include_str!("light.wgsl"));
+        pipelines.add_shader(&init.device, SKYBOX_SHADER, include_str!("skybox.wgsl"));
+        pipelines.add_layout(MODEL_LAYOUT, init.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("Render Pipeline Layout"),
+            bind_group_layouts: &[
+                &uniform_bind_group_layout,
+                &texture_bind_group_layout,
+                shadows.bind_group_layout(),
+                environment.bind_group_layout(),
+            ],
+            push_constant_ranges: &[],
+        }));
+        pipelines.add_layout(SKYBOX_LAYOUT, init.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("skybox pipeline"),
+            bind_group_layouts: &[environment.bind_group_layout()],
+            push_constant_ranges: &[],
+        }));
+        pipelines.add_layout(LIGHT_LAYOUT, init.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("light pipeline"),
+            bind_group_layouts: &[&uniform_bind_group_layout],
+            push_constant_ranges: &[],
+        }));
+        let depth_mode = transforms::DepthMode::default();
+        let targets = RenderTargets::new(&init.device, &init.config, depth_mode.format(), 1);
+
+        let mut instances = instancing::craete_instances().into_iter().collect::<instancing::InstanceSet>();
+        instances.upload(&init.device, &init.queue);
+        let mut state = Self {
             init,
-            pipeline,
-            light_render_pipeline,
+            pipelines,
+            depth_mode,
+            targets,
             instances,
             vertex_uniform_buffer,
             fragment_uniform_buffer,
-            instance_buffer,
             uniform_bind_group,
             obj_model,
+            material_layout: texture_bind_group_layout,
             model_mat,
             view_mat,
             project_mat,
-            direct: "".into(),
+            projection,
+            projection_distance: (camera.position - camera.direction).magnitude(),
+            finite_far: 100.0,
             camera,
-            light_instance: eye_position.into(),
-        }
+            camera_controller,
+            elapsed: std::time::Duration::ZERO,
+            lights,
+            ambient,
+            light_uniform_buffer,
+            shadows,
+            environment,
+        };
+        state.prepare_pipelines();
+        Ok(state)
     }
 
     pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
         if new_size.width > 0 && new_size.height > 0 {
-            self.init.size = new_size;
-            self.init.config.width = new_size.width;
-            self.init.config.height = new_size.height;
-            self.init.surface.configure(&self.init.device, &self.init.config);
+            self.init.resize(new_size);
+            self.targets.resize(&self.init.device, new_size.width, new_size.height);
+            self.camera_controller.set_viewport(new_size.width, new_size.height);
 
-            self.project_mat = transforms::create_projection(new_size.width as f32 / new_size.height as f32, IS_PERSPECTIVE);
+            self.project_mat = self.projection_matrix();
         }
     }
 
-    #[allow(unused_variables)]
     pub fn input(&mut self, event: &WindowEvent) -> bool {
         match event {
             WindowEvent::KeyboardInput {
@@ -350,103 +350,65 @@ impl State {
                     },
                 ..
             } => {
-                let is_pressed = *state == ElementState::Pressed;
                 match keycode {
-                    VirtualKeyCode::Space => {
-                        self.direct = "Forward".into();
-                        true
-                    }
-                    VirtualKeyCode::LShift => {
-                        self.direct = "Backward".into();
-                        true
+                    VirtualKeyCode::P | VirtualKeyCode::Z | VirtualKeyCode::M | VirtualKeyCode::B => {
+                        if *state == ElementState::Pressed {
+                            match keycode {
+                                VirtualKeyCode::P => self.toggle_orthographic(),
+                                VirtualKeyCode::Z => self.set_depth_mode(match self.depth_mode {
+                                    transforms::DepthMode::Standard => transforms::DepthMode::ReverseZ,
+                                    transforms::DepthMode::ReverseZ => transforms::DepthMode::Standard,
+                                }),
+                                VirtualKeyCode::B => self.cycle_blend_mode(),
+                                _ => self.cycle_sample_count(),
+                            }
+                        }
+                        return true;
                     }
-                    VirtualKeyCode::W | VirtualKeyCode::Up => {
-                        self.direct = "Up".into();
-                        true
-                    }
-                    VirtualKeyCode::A | VirtualKeyCode::Left => {
-                        self.direct = "Left".into();
-                        true
-                    }
-                    VirtualKeyCode::S | VirtualKeyCode::Down => {
-                        self.direct = "Down".into();
-                        true
-                    }
-                    VirtualKeyCode::D | VirtualKeyCode::Right => {
-                        self.direct = "Right".into();
-                        true
-                    }
-                    _ => false,
-                }
-            },
-            WindowEvent::MouseWheel { 
-                delta: MouseScrollDelta::LineDelta(_, y),
-                ..
-            } => {
-                if *y < 0.0 {
-                    self.direct = "Backward".into();
-                } else {
-                    self.direct = "Forward".into();
+                    _ => {}
                 }
+                self.camera_controller.process_keyboard(*keycode, *state)
+            }
+            WindowEvent::MouseInput { button, state, .. } => {
+                self.camera_controller.process_mouse_button(*button, *state)
+            }
+            WindowEvent::CursorMoved { position, .. } => {
+                self.camera_controller.process_cursor_moved(position.x, position.y);
                 true
-            },
+            }
+            WindowEvent::CursorLeft { .. } => {
+                self.camera_controller.process_cursor_left();
+                true
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                self.camera_controller.process_scroll(delta);
+                true
+            }
             _ => false,
         }
     }
 
+    // raw mouse motion, used for mouse look
+    pub fn device_input(&mut self, event: &DeviceEvent) {
+        if let DeviceEvent::MouseMotion { delta } = event {
+            self.camera_controller.process_mouse_motion(delta.0, delta.1);
+        }
+    }
+
+    // `dt` is the total time since start
     pub fn update(&mut self, dt: std::time::Duration) {
+        let frame_time = dt.saturating_sub(self.elapsed);
+        self.elapsed = dt;
+        self.camera_controller.update_camera(&mut self.camera, frame_time);
+        self.view_mat = transforms::create_view(self.camera.position, self.camera.direction, self.camera.up);
+        if let transforms::Projection::Orthographic { .. } = self.projection {
+            self.project_mat = self.projection_matrix();
+        }
+        let eye_position: [f32; 3] = self.camera.position.into();
+        self.init.queue.write_buffer(&self.fragment_uniform_buffer, 0, bytemuck::cast_slice(&[eye_position]));
+
         // update uniform buffer
         let dt = ANIMATION_SPEED * dt.as_secs_f32(); 
-        // let mut translation = [0.0, 0.0, 0.0];
-        // let mut rotation = [0.0, 0.0, 0.0];
-        let forward = self.camera.direction - self.camera.position;
-        self.view_mat = match &self.direct as &str {
-            "Forward" => {
-                self.direct = "".into();
-                self.camera.position += forward.normalize();
-                transforms::create_view(self.camera.position, self.camera.direction, self.camera.up)
-            },
-            "Backward" => {
-                self.direct = "".into();
-                self.camera.position -= forward.normalize();
-                transforms::create_view(self.camera.position, self.camera.direction, self.camera.up)
-            },
-            "Up" => {
-                self.direct = "".into();
-                self.camera.position = self.camera.direction + (
-                    -forward + self.camera.up
-                ).normalize() * forward.magnitude();
-                transforms::create_view(self.camera.position, self.camera.direction, self.camera.up)
-            },
-            "Down" => {
-                self.direct = "".into();
-                self.camera.position = self.camera.direction + (
-                    -forward - self.camera.up
-                ).normalize() * forward.magnitude();
-                transforms::create_view(self.camera.position, self.camera.direction, self.camera.up)
-            },
-            "Left" => {
-                self.direct = "".into();
-                // let r = cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_y(), cgmath::Deg(-dt));
-                // self.camera.position = r.rotate_point(self.camera.position);
-                self.camera.position = self.camera.direction + (
-                    -forward - forward.normalize().cross(self.camera.up)
-                ).normalize() * forward.magnitude();
-                transforms::create_view(self.camera.position, self.camera.direction, self.camera.up)
-            },
-            "Right" => {
-                self.direct = "".into();
-                // let r = cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_y(), cgmath::Deg(dt));
-                // self.camera.position = r.rotate_point(self.camera.position);
-                self.camera.position = self.camera.direction + (
-                    -forward + forward.normalize().cross(self.camera.up)
-                ).normalize() * forward.magnitude();
-                transforms::create_view(self.camera.position, self.camera.direction, self.camera.up)
-            },
-            _ => {
-                self.view_mat
-            }
-        };
         // self.model_mat = self.model_mat * transforms::create_transforms(translation, rotation, [1.0, 1.0, 1.0]);
         // self.model_mat = model_mat;
         // let mvp_mat = self.project_mat * self.view_mat * self.model_mat;        
@@ -465,89 +427,396 @@ impl State {
         // self.light_instance = self.camera.direction + (
         //     -forward + forward.normalize().cross(self.camera.up) * dt
         // ).normalize() * forward.magnitude();
+        // lights circle around the y axis
         let q = cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_y(), cgmath::Deg(dt));
-        self.light_instance = q.rotate_point(self.light_instance);
-        // println!("{:?}", self.light_instance);
-        let light_pos: [f32; 3] = self.light_instance.into();
-        self.init.queue.write_buffer(&self.fragment_uniform_buffer, 0, bytemuck::cast_slice(&[light_pos]));
+        for light in &mut self.lights {
+            match &mut light.kind {
+                lights::LightKind::Point { position, .. } => *position = q.rotate_point(*position),
+                lights::LightKind::Spot { position, direction, .. } => {
+                    *position = q.rotate_point(*position);
+                    *direction = q.rotate_vector(*direction);
+                }
+                lights::LightKind::Directional { .. } => {}
+            }
+        }
+        let mut lights_uniform = lights::LightsUniform::new(self.ambient, &self.lights);
+        self.shadows.update(
+            &self.init.queue,
+            &self.lights,
+            &self.camera,
+            &self.view_projection(),
+            self.init.config.width as f32 / self.init.config.height as f32,
+            self.model_mat,
+            &mut lights_uniform,
+        );
+        self.init.queue.write_buffer(&self.light_uniform_buffer, 0, bytemuck::cast_slice(&[lights_uniform]));
+        self.environment.update(
+            &self.init.queue,
+            self.view_mat,
+            &self.view_projection(),
+            self.init.config.width as f32 / self.init.config.height as f32,
+        );
 
-        // for inst in &mut self.instances {
+        // for inst in self.instances.iter_mut() {
         //     let amount = cgmath::Quaternion::from_angle_y(Rad(ANIMATION_SPEED));
         //     let current = inst.rotation;
         //     inst.rotation = amount * current;
         // }
-        // let inst_data = self.instances
-        //     .iter().map(Instance::to_raw)
-        //     .collect::<Vec<_>>();
-        // self.init.queue.write_buffer(&self.instance_buffer, 0, bytemuck::cast_slice(&inst_data));
     }
 
     pub fn render(&mut self) -> Result<(), wgpu::SurfaceError> {
         //let output = self.init.surface.get_current_frame()?.output;
-        let output = self.init.surface.get_current_texture()?;
-        let view = output
-            .texture
-            .create_view(&wgpu::TextureViewDescriptor::default());  
-        let depth_texture = self.init.device.create_texture(&wgpu::TextureDescriptor {
-            view_formats: &[],
-            size: wgpu::Extent3d {
-                width: self.init.config.width,
-                height: self.init.config.height,
-                depth_or_array_layers: 1,
+        let output = self.init.current_frame()?;
+        self.upload_instances();
+        self.draw(&output.view);
+        output.present();
+
+        Ok(())
+    }
+
+    // Node transforms and instance changes since the last frame.
+    fn upload_instances(&mut self) {
+        self.instances.set_nodes(self.obj_model.world_transforms());
+        self.instances.upload(&self.init.device, &self.init.queue);
+    }
+
+    // Renders the scene and writes it to a PNG file.
+    pub fn render_to_file<P: AsRef<std::path::Path>>(&mut self, path: P) -> anyhow::Result<()> {
+        self.render_image()?.save(path)?;
+        Ok(())
+    }
+
+    // Draws the scene into a texture that can be read back. Swapchain images
+    // can't be copied from on every backend, so this doesn't reuse the frame
+    // shown by render.
+    pub fn render_image(&mut self) -> anyhow::Result<image::RgbaImage> {
+        self.upload_instances();
+        let target = transforms::create_offscreen_texture(&self.init.device, &self.init.config);
+        self.draw(&target.create_view(&wgpu::TextureViewDescriptor::default()));
+        texture::read_texture(&self.init.device, &self.init.queue, &target)
+    }
+
+    pub fn set_instances(&mut self, instances: Vec<instancing::Instance>) {
+        self.instances.clear();
+        for instance in instances {
+            self.instances.add(instance);
+        }
+    }
+
+    pub fn set_projection(&mut self, projection: transforms::Projection) {
+        self.projection = projection;
+        self.projection_distance = self.orbit_distance();
+        if let Some(fovy) = projection.fovy() {
+            self.camera_controller.fovy = fovy;
+        }
+        self.project_mat = self.projection_matrix();
+    }
+
+    fn orbit_distance(&self) -> f32 {
+        (self.camera.position - self.camera.direction).magnitude().max(f32::EPSILON)
+    }
+
+    // The projection as drawn. Orthographic views don't get smaller with
+    // distance, so their zoom follows the orbit distance relative to when the
+    // projection was set to keep scroll zoom working.
+    fn view_projection(&self) -> transforms::Projection {
+        self.projection.zoomed(self.projection_distance / self.orbit_distance())
+    }
+
+    // Finite projections get their depth range flipped for reverse-Z.
+    fn projection_matrix(&self) -> Matrix4<f32> {
+        let projection = self.view_projection();
+        let matrix = projection.matrix(self.init.config.width as f32 / self.init.config.height as f32);
+        match self.depth_mode {
+            transforms::DepthMode::ReverseZ if !projection.is_reverse_z() => transforms::REVERSE_DEPTH_MATRIX * matrix,
+            _ => matrix,
+        }
+    }
+
+    // Switches between perspective and an orthographic view that keeps the
+    // target the same size on screen.
+    pub fn toggle_orthographic(&mut self) {
+        let fovy = self.camera_controller.fovy;
+        let projection = match self.projection {
+            transforms::Projection::Orthographic { near, far, .. } => match self.depth_mode {
+                transforms::DepthMode::Standard => transforms::Projection::Perspective { fovy, near, far },
+                transforms::DepthMode::ReverseZ => {
+                    self.finite_far = far;
+                    transforms::Projection::InfiniteReverseZ { fovy, near }
+                }
             },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format:wgpu::TextureFormat::Depth24Plus,
-            usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
-            label: None,
+            transforms::Projection::Perspective { fovy, near, far } => {
+                transforms::Projection::orthographic_from(fovy, self.orbit_distance(), near, far)
+            }
+            transforms::Projection::InfiniteReverseZ { fovy, near } => {
+                transforms::Projection::orthographic_from(fovy, self.orbit_distance(), near, self.finite_far)
+            }
+        };
+        self.set_projection(projection);
+    }
+
+    // Rebuilds the pipelines for the new depth buffer layout. Perspective
+    // views switch to an infinite far plane for reverse-Z and get their old
+    // far plane back when leaving it; orthographic views stay as they are.
+    pub fn set_depth_mode(&mut self, depth_mode: transforms::DepthMode) {
+        if depth_mode == self.depth_mode {
+            return;
+        }
+        self.depth_mode = depth_mode;
+        if !self.init.supported_sample_counts(depth_mode.format()).contains(&self.sample_count()) {
+            self.targets.set_sample_count(&self.init.device, 1);
+        }
+        self.targets.set_depth_format(&self.init.device, depth_mode.format());
+        self.prepare_pipelines();
+        match (depth_mode, self.projection) {
+            (transforms::DepthMode::ReverseZ, transforms::Projection::Perspective { fovy, near, far }) => {
+                self.finite_far = far;
+                self.set_projection(transforms::Projection::InfiniteReverseZ { fovy, near });
+            }
+            (transforms::DepthMode::Standard, transforms::Projection::InfiniteReverseZ { fovy, near }) => {
+                self.set_projection(transforms::Projection::Perspective { fovy, near, far: self.finite_far });
+            }
+            _ => self.project_mat = self.projection_matrix(),
+        }
+    }
+
+    pub fn sample_count(&self) -> u32 {
+        self.targets.sample_count()
+    }
+
+    // 1 turns multisampling off. Fails for counts the adapter can't do with
+    // the surface and depth formats, see InitWgpu::supported_sample_counts.
+    pub fn set_sample_count(&mut self, sample_count: u32) -> anyhow::Result<()> {
+        let supported = self.init.supported_sample_counts(self.depth_mode.format());
+        if !supported.contains(&sample_count) {
+            anyhow::bail!("{}x MSAA is not supported by this adapter (supported: {:?})", sample_count, supported);
+        }
+        if sample_count != self.targets.sample_count() {
+            self.targets.set_sample_count(&self.init.device, sample_count);
+            self.prepare_pipelines();
+        }
+        Ok(())
+    }
+
+    // steps to the next supported sample count, wrapping back to 1
+    pub fn cycle_sample_count(&mut self) {
+        let supported = self.init.supported_sample_counts(self.depth_mode.format());
+        let next = supported.iter().copied().find(|&count| count > self.sample_count()).unwrap_or(1);
+        self.set_sample_count(next).unwrap();
+    }
+
+    // Steps every material to the blend preset after the first one's.
+    pub fn cycle_blend_mode(&mut self) {
+        self.edit_materials(|materials| {
+            let next = materials.first().map_or_else(Default::default, |m| m.blend.next());
+            for material in materials {
+                log::info!("{}: {:?} blending", material.name, next);
+                material.blend = next;
+            }
         });
-        let depth_view = depth_texture.create_view(&wgpu::TextureViewDescriptor::default());
-        
+    }
+
+    // Pipeline variant a material asks for, in the current depth and MSAA setup.
+    fn model_pipeline_key(&self, material: &model::Material) -> PipelineKey {
+        PipelineKey {
+            depth: Some(self.depth_mode),
+            depth_write: material.blend == pipeline::BlendMode::Replace,
+            blend: material.blend,
+            cull_mode: if material.double_sided { None } else { Some(wgpu::Face::Back) },
+            sample_count: self.targets.sample_count(),
+            fs_entry: match material.shading_model {
+                model::ShadingModel::BlinnPhong => "fs_main",
+                model::ShadingModel::MetallicRoughness => "fs_pbr",
+            },
+            ..PipelineKey::new(
+                MODEL_SHADER,
+                MODEL_LAYOUT,
+                vec![model::ModelVertex::desc(), instancing::InstanceRaw::desc()],
+                self.init.config.format,
+            )
+        }
+    }
+
+    fn light_pipeline_key(&self) -> PipelineKey {
+        PipelineKey {
+            depth: Some(self.depth_mode),
+            sample_count: self.targets.sample_count(),
+            ..PipelineKey::new(LIGHT_SHADER, LIGHT_LAYOUT, vec![model::ModelVertex::desc()], self.init.config.format)
+        }
+    }
+
+    // Drawn first and without depth writes, everything else covers it.
+    fn skybox_pipeline_key(&self) -> PipelineKey {
+        PipelineKey {
+            depth: Some(self.depth_mode),
+            depth_write: false,
+            depth_compare: Some(wgpu::CompareFunction::Always),
+            cull_mode: None,
+            sample_count: self.targets.sample_count(),
+            ..PipelineKey::new(SKYBOX_SHADER, SKYBOX_LAYOUT, vec![], self.init.config.format)
+        }
+    }
+
+    // Builds every pipeline draw() will look up and drops the ones it no
+    // longer will. Called wherever a key changes: depth mode, sample count,
+    // model and material edits.
+    fn prepare_pipelines(&mut self) {
+        let mut keys: Vec<PipelineKey> = self.obj_model.materials.iter().map(|m| self.model_pipeline_key(m)).collect();
+        keys.push(self.light_pipeline_key());
+        keys.push(self.skybox_pipeline_key());
+        for key in &keys {
+            self.pipelines.get_or_create(&self.init.device, key);
+        }
+        self.pipelines.retain(&keys);
+    }
+
+    // Depth of the last drawn frame, for post-processing passes; none of
+    // the passes here read it yet.
+    #[allow(dead_code)]
+    pub fn depth_texture(&self) -> &texture::Texture {
+        self.targets.depth()
+    }
+
+    // Only the first lights::MAX_LIGHTS are drawn. Uploaded with the next update.
+    pub fn set_lights(&mut self, lights: Vec<lights::Light>) {
+        self.lights = lights;
+    }
+
+    // Uploads the edited uniforms and builds the pipeline variants the
+    // materials now need.
+    pub fn edit_materials(&mut self, edit: impl FnOnce(&mut [model::Material])) {
+        edit(&mut self.obj_model.materials);
+        for material in &self.obj_model.materials {
+            material.write_uniform(&self.init.queue);
+        }
+        self.prepare_pipelines();
+    }
+
+    // Scales the light the environment adds, [1, 1, 1] uses it as is.
+    pub fn set_ambient(&mut self, ambient: [f32; 3]) {
+        self.ambient = ambient;
+    }
+
+    // Lights the scene with an equirectangular HDR image and shows it as the
+    // skybox, see resources::load_hdr.
+    pub fn set_environment(&self, image: &image::Rgba32FImage) {
+        self.environment.load(&self.init.device, &self.init.queue, image);
+    }
+
+    // Replaces the drawn model with an OBJ or glTF/GLB file.
+    pub async fn set_model(&mut self, file_name: &str) -> anyhow::Result<()> {
+        self.obj_model = resources::load_model(file_name, &self.init.device, &self.init.queue, &self.material_layout).await?;
+        self.prepare_pipelines();
+        Ok(())
+    }
+
+    pub fn shadow_settings(&self) -> ShadowSettings {
+        self.shadows.settings()
+    }
+
+    // Applied with the next update.
+    pub fn set_shadow_settings(&mut self, settings: ShadowSettings) {
+        self.shadows.set_settings(&self.init.device, settings);
+    }
+
+    fn draw(&self, view: &wgpu::TextureView) {
         let mut encoder = self
             .init.device
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("Render Encoder"),
             });
+        self.shadows.render(&mut encoder, &self.obj_model, &self.instances);
 
         {
             let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
                 label: Some("Render Pass"),
-                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
-                    view: &view,
-                    resolve_target: None,
-                    ops: wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(wgpu::Color {
-                            r: 0.2,
-                            g: 0.247,
-                            b: 0.314,
-                            a: 1.0,
-                        }),
-                        store: true,
-                    },
-                })],
-                //depth_stencil_attachment: None,
-                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
-                    view: &depth_view,
-                    depth_ops: Some(wgpu::Operations {
-                        load: wgpu::LoadOp::Clear(1.0),
-                        store: false,
-                    }),
-                    stencil_ops: None,
-                }),
+                // the skybox covers every pixel
+                color_attachments: &[Some(self.targets.color_attachment(view, wgpu::Color::BLACK))],
+                depth_stencil_attachment: Some(self.targets.depth_attachment(self.depth_mode.clear_value())),
             });
 
-            render_pass.set_vertex_buffer(1, self.instance_buffer.slice(..));           
-            render_pass.set_pipeline(&self.pipeline);
-            render_pass.draw_model_instanced(&self.obj_model, 0..self.instances.len() as u32, &self.uniform_bind_group);
+            render_pass.set_pipeline(self.pipelines.get(&self.skybox_pipeline_key()).unwrap());
+            render_pass.set_bind_group(0, self.environment.bind_group(), &[]);
+            render_pass.draw(0..3, 0..1);
+
+            if let (false, Some(instance_buffer)) = (self.instances.is_empty(), self.instances.buffer()) {
+                render_pass.set_vertex_buffer(1, instance_buffer.slice(..));
+                render_pass.set_bind_group(2, self.shadows.bind_group(), &[]);
+                render_pass.set_bind_group(3, self.environment.bind_group(), &[]);
+                // opaque meshes first so blended ones have something to blend over
+                for blended in [false, true] {
+                    for mesh in &self.obj_model.meshes {
+                        let material = &self.obj_model.materials[mesh.material];
+                        if (material.blend != pipeline::BlendMode::Replace) != blended {
+                            continue;
+                        }
+                        render_pass.set_pipeline(self.pipelines.get(&self.model_pipeline_key(material)).unwrap());
+                        render_pass.draw_mesh_instanced(mesh, material, self.instances.node_range(mesh.node), &self.uniform_bind_group);
+                    }
+                }
+            }
             
-            render_pass.set_pipeline(&self.light_render_pipeline);
-            render_pass.draw_light_model(&self.obj_model, 0..1, &self.uniform_bind_group);
+            render_pass.set_pipeline(self.pipelines.get(&self.light_pipeline_key()).unwrap());
+            let markers = self.lights.len().min(lights::MAX_LIGHTS) as u32;
+            render_pass.draw_light_model(&self.obj_model, 0..markers, &self.uniform_bind_group);
         }
 
         self.init.queue.submit(iter::once(encoder.finish()));
-        output.present();
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        Ok(())
+    #[test]
+    fn orthographic_zoom_survives_update() {
+        let _guard = crate::golden::GPU.lock().unwrap_or_else(|e| e.into_inner());
+        let mut state = pollster::block_on(State::new_headless(64, 48, &transforms::InitConfig::headless())).unwrap();
+        let aspect = 64.0 / 48.0;
+        let projection = transforms::Projection::Orthographic { height: 4.0, zoom: 2.0, near: 0.1, far: 100.0 };
+        state.set_projection(projection);
+        state.update(std::time::Duration::ZERO);
+        assert_eq!(state.projection, projection);
+        assert!(state.project_mat.abs_diff_eq(&projection.matrix(aspect), 1e-6));
+
+        // moving twice as far away shows twice as much
+        state.camera.position = state.camera.direction + (state.camera.position - state.camera.direction) * 2.0;
+        state.update(std::time::Duration::ZERO);
+        assert_eq!(state.projection, projection);
+        assert!(state.project_mat.abs_diff_eq(&projection.zoomed(0.5).matrix(aspect), 1e-5));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn depth_texture_follows_depth_mode() {
+        let _guard = crate::golden::GPU.lock().unwrap_or_else(|e| e.into_inner());
+        let mut state = pollster::block_on(State::new_headless(64, 48, &transforms::InitConfig::headless())).unwrap();
+        state.set_depth_mode(transforms::DepthMode::ReverseZ);
+        let depth = &state.depth_texture().texture;
+        assert_eq!(depth.format(), transforms::DepthMode::ReverseZ.format());
+        assert_eq!((depth.width(), depth.height()), (64, 48));
+    }
+
+    #[test]
+    fn depth_mode_round_trip_restores_projection() {
+        let _guard = crate::golden::GPU.lock().unwrap_or_else(|e| e.into_inner());
+        let mut state = pollster::block_on(State::new_headless(64, 48, &transforms::InitConfig::headless())).unwrap();
+        let perspective = transforms::Projection::Perspective { fovy: Rad(1.0), near: 0.5, far: 40.0 };
+        state.set_projection(perspective);
+        state.set_depth_mode(transforms::DepthMode::ReverseZ);
+        assert_eq!(state.projection, transforms::Projection::InfiniteReverseZ { fovy: Rad(1.0), near: 0.5 });
+        state.set_depth_mode(transforms::DepthMode::Standard);
+        assert_eq!(state.projection, perspective);
+
+        // orthographic views keep their box, only the depth range flips
+        let ortho = transforms::Projection::Orthographic { height: 4.0, zoom: 2.0, near: 0.5, far: 40.0 };
+        state.set_projection(ortho);
+        state.set_depth_mode(transforms::DepthMode::ReverseZ);
+        assert_eq!(state.projection, ortho);
+        let near = state.project_mat * Vector4::new(0.0, 0.0, -0.5, 1.0);
+        let far = state.project_mat * Vector4::new(0.0, 0.0, -40.0, 1.0);
+        assert!((near.z - 1.0).abs() < 1e-5 && far.z.abs() < 1e-5, "{:?} {:?}", near, far);
+        state.set_depth_mode(transforms::DepthMode::Standard);
+        assert_eq!(state.projection, ortho);
+        assert!(state.project_mat.abs_diff_eq(&ortho.matrix(64.0 / 48.0), 1e-6));
+    }
+}
@@ -0,0 +1,636 @@
+use std::num::NonZeroU64;
+
+use cgmath::*;
+use wgpu::util::DeviceExt;
+
+use crate::{pipeline, transforms};
+
+// mips of the specular cubemap, roughness 0 to 1 in even steps; keep in sync
+// with lightning.wgsl
+pub const PREFILTERED_MIPS: u32 = 5;
+const BRDF_LUT_SIZE: u32 = 128;
+const BRDF_LUT_SAMPLES: u32 = 256;
+// half floats are renderable and filterable on every backend
+const FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Rgba16Float;
+// shown until an HDR image is loaded
+pub const DEFAULT_COLOR: [f32; 3] = [0.2, 0.247, 0.314];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct EnvironmentSettings {
+    // face size of the cubemap the skybox shows
+    pub cube_size: u32,
+    pub irradiance_size: u32,
+    // face size of the sharpest prefiltered mip, every further mip halves it
+    pub prefiltered_size: u32,
+    // GGX samples per texel of the prefiltered mips
+    pub specular_samples: u32,
+    // steps around the normal while integrating irradiance, a quarter of
+    // that from the normal to the horizon
+    pub irradiance_samples: u32,
+}
+
+impl Default for EnvironmentSettings {
+    fn default() -> Self {
+        Self {
+            cube_size: 512,
+            irradiance_size: 32,
+            prefiltered_size: 128,
+            specular_samples: 64,
+            irradiance_samples: 64,
+        }
+    }
+}
+
+// Matches the Face struct in environment.wgsl.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, bytemuck::Pod, bytemuck::Zeroable)]
+struct FaceUniform {
+    face: u32,
+    samples: u32,
+    roughness: f32,
+    source_lod: f32,
+    source_size: f32,
+    _padding: [f32; 3],
+}
+
+// Matches the Sky struct in skybox.wgsl.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct SkyUniform {
+    inv_view_proj: [[f32; 4]; 4],
+}
+
+// Inverse view-projection the skybox turns screen positions into directions
+// with. The camera translation is dropped so the sky stays infinitely far
+// away; orthographic views have no directions to look up and get the default
+// perspective instead.
+pub fn sky_matrix(view_mat: Matrix4<f32>, projection: &transforms::Projection, aspect: f32) -> Matrix4<f32> {
+    let projection = match projection {
+        transforms::Projection::Orthographic { .. } => transforms::Projection::default(),
+        projection => *projection,
+    };
+    let mut rotation = view_mat;
+    rotation.w = Vector4::unit_w();
+    (projection.matrix(aspect) * rotation).invert().unwrap_or_else(Matrix4::identity)
+}
+
+// IEEE half float bits, rounded to nearest and clamped to the largest finite
+// value since HDR images can hold brighter pixels than f16 does.
+pub fn f16_bits(value: f32) -> u16 {
+    let sign = ((value.to_bits() >> 16) & 0x8000) as u16;
+    let value = value.abs().min(65504.0);
+    if value < 6.103_515_6e-5 {
+        // subnormal, steps of 2^-24
+        return sign | (value / 5.960_464_5e-8).round() as u16;
+    }
+    let bits = value.to_bits();
+    let exponent = (bits >> 23) + 15 - 127;
+    let mantissa = bits & 0x7f_ffff;
+    // a carry out of the mantissa correctly bumps the exponent
+    let half = ((exponent << 10) | (mantissa >> 13)) + ((mantissa >> 12) & 1);
+    sign | half as u16
+}
+
+// Box filtered mip chain down to 1x1, the conversion reads the level that
+// matches each cube mip.
+fn equirect_mips(image: &image::Rgba32FImage) -> Vec<image::Rgba32FImage> {
+    let mut mips = vec![image.clone()];
+    loop {
+        let last = mips.last().unwrap();
+        if last.width() == 1 && last.height() == 1 {
+            return mips;
+        }
+        let (width, height) = ((last.width() / 2).max(1), (last.height() / 2).max(1));
+        let next = image::imageops::resize(last, width, height, image::imageops::FilterType::Triangle);
+        mips.push(next);
+    }
+}
+
+fn mip_count(size: u32) -> u32 {
+    32 - size.leading_zeros()
+}
+
+// Image-based lighting from an equirectangular HDR image: the image as a
+// cubemap for the skybox, its cosine convolution for diffuse light, GGX
+// prefiltered mips for specular light and the BRDF lookup table of the
+// split-sum approximation. Shaders read all of it from one bind group.
+pub struct Environment {
+    settings: EnvironmentSettings,
+    cube: wgpu::Texture,
+    irradiance: wgpu::Texture,
+    prefiltered: wgpu::Texture,
+    sampler: wgpu::Sampler,
+    sky_buffer: wgpu::Buffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+}
+
+impl Environment {
+    // Starts out as a uniform DEFAULT_COLOR environment.
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, settings: EnvironmentSettings) -> Self {
+        let cube = create_cube(device, "environment cube", settings.cube_size, mip_count(settings.cube_size));
+        let irradiance = create_cube(device, "irradiance cube", settings.irradiance_size, 1);
+        let prefiltered = create_cube(device, "prefiltered cube", settings.prefiltered_size, PREFILTERED_MIPS);
+        let brdf_lut = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some("brdf lut"),
+            size: wgpu::Extent3d {
+                width: BRDF_LUT_SIZE,
+                height: BRDF_LUT_SIZE,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rg16Float,
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("environment sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+        let sky_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Sky Uniform Buffer"),
+            size: std::mem::size_of::<SkyUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let texture_entry = |binding, view_dimension| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStages::FRAGMENT,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension,
+                sample_type: wgpu::TextureSampleType::Float { filterable: true },
+            },
+            count: None,
+        };
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                texture_entry(0, wgpu::TextureViewDimension::Cube),
+                texture_entry(1, wgpu::TextureViewDimension::Cube),
+                texture_entry(2, wgpu::TextureViewDimension::D2),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                    count: None,
+                },
+                // the skybox reads the unfiltered cube and its own matrix
+                texture_entry(4, wgpu::TextureViewDimension::Cube),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 5,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("environment_bind_group_layout"),
+        });
+        let (irradiance_view, prefiltered_view, cube_view) = (cube_view(&irradiance), cube_view(&prefiltered), cube_view(&cube));
+        let brdf_lut_view = brdf_lut.create_view(&wgpu::TextureViewDescriptor::default());
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&irradiance_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&prefiltered_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&brdf_lut_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: wgpu::BindingResource::TextureView(&cube_view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: sky_buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("environment_bind_group"),
+        });
+
+        // the lookup table only depends on the BRDF, not on the image
+        let filters = Filters::new(device);
+        let brdf_pass = FilterPass {
+            uniform: FaceUniform {
+                samples: BRDF_LUT_SAMPLES,
+                ..Default::default()
+            },
+            target: brdf_lut.create_view(&wgpu::TextureViewDescriptor::default()),
+            pipeline: &filters.brdf,
+            source: None,
+        };
+        filters.run(device, queue, &[brdf_pass]);
+
+        let environment = Self {
+            settings,
+            cube,
+            irradiance,
+            prefiltered,
+            sampler,
+            sky_buffer,
+            bind_group_layout,
+            bind_group,
+        };
+        let [r, g, b] = DEFAULT_COLOR;
+        environment.load(device, queue, &image::Rgba32FImage::from_pixel(2, 1, image::Rgba([r, g, b, 1.0])));
+        environment
+    }
+
+    pub fn bind_group_layout(&self) -> &wgpu::BindGroupLayout {
+        &self.bind_group_layout
+    }
+
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+
+    // Replaces the environment with an equirectangular image, longitude
+    // along x starting at -x and the zenith in the top row. The cubemaps are
+    // refilled in place, so the bind group stays valid.
+    pub fn load(&self, device: &wgpu::Device, queue: &wgpu::Queue, image: &image::Rgba32FImage) {
+        let settings = self.settings;
+        let filters = Filters::new(device);
+        let equirect = create_equirect(device, queue, image);
+        let equirect_view = equirect.create_view(&wgpu::TextureViewDescriptor::default());
+        let equirect_group = filters.source_group(device, &filters.equirect_layout, 0, &equirect_view, &self.sampler);
+        let cube_group = filters.source_group(device, &filters.cube_layout, 1, &cube_view(&self.cube), &self.sampler);
+
+        // log2 of how many source texels fall on one target texel
+        let lod = |source: f32, target: f32| (source / target).log2().max(0.0);
+        let mut passes = Vec::new();
+        // the equirect spans four faces horizontally
+        for mip in 0..mip_count(settings.cube_size) {
+            let size = (settings.cube_size >> mip).max(1);
+            for face in 0..6 {
+                passes.push(FilterPass {
+                    uniform: FaceUniform {
+                        face,
+                        source_lod: lod(image.width() as f32, 4.0 * size as f32),
+                        ..Default::default()
+                    },
+                    target: face_view(&self.cube, mip, face),
+                    pipeline: &filters.equirect,
+                    source: Some(&equirect_group),
+                });
+            }
+        }
+        // read the mip whose texels are about as wide as the steps between samples
+        let irradiance_lod = lod(settings.cube_size as f32, (settings.irradiance_samples / 4).max(1) as f32);
+        for face in 0..6 {
+            passes.push(FilterPass {
+                uniform: FaceUniform {
+                    face,
+                    samples: settings.irradiance_samples,
+                    source_lod: irradiance_lod,
+                    ..Default::default()
+                },
+                target: face_view(&self.irradiance, 0, face),
+                pipeline: &filters.irradiance,
+                source: Some(&cube_group),
+            });
+        }
+        for mip in 0..PREFILTERED_MIPS {
+            for face in 0..6 {
+                passes.push(FilterPass {
+                    uniform: FaceUniform {
+                        face,
+                        samples: settings.specular_samples,
+                        roughness: mip as f32 / (PREFILTERED_MIPS - 1) as f32,
+                        source_lod: lod(settings.cube_size as f32, (settings.prefiltered_size >> mip).max(1) as f32),
+                        source_size: settings.cube_size as f32,
+                        ..Default::default()
+                    },
+                    target: face_view(&self.prefiltered, mip, face),
+                    pipeline: &filters.prefilter,
+                    source: Some(&cube_group),
+                });
+            }
+        }
+        filters.run(device, queue, &passes);
+    }
+
+    pub fn update(&self, queue: &wgpu::Queue, view_mat: Matrix4<f32>, projection: &transforms::Projection, aspect: f32) {
+        let sky = SkyUniform {
+            inv_view_proj: sky_matrix(view_mat, projection, aspect).into(),
+        };
+        queue.write_buffer(&self.sky_buffer, 0, bytemuck::cast_slice(&[sky]));
+    }
+}
+
+// One fullscreen triangle into `target`.
+struct FilterPass<'a> {
+    uniform: FaceUniform,
+    target: wgpu::TextureView,
+    pipeline: &'a wgpu::RenderPipeline,
+    source: Option<&'a wgpu::BindGroup>,
+}
+
+// Pipelines of environment.wgsl, only kept while the maps are filled.
+struct Filters {
+    face_layout: wgpu::BindGroupLayout,
+    equirect_layout: wgpu::BindGroupLayout,
+    cube_layout: wgpu::BindGroupLayout,
+    equirect: wgpu::RenderPipeline,
+    irradiance: wgpu::RenderPipeline,
+    prefilter: wgpu::RenderPipeline,
+    brdf: wgpu::RenderPipeline,
+}
+
+impl Filters {
+    fn new(device: &wgpu::Device) -> Self {
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some("Environment Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("environment.wgsl").into()),
+        });
+        let face_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: NonZeroU64::new(std::mem::size_of::<FaceUniform>() as u64),
+                },
+                count: None,
+            }],
+            label: Some("environment_face_bind_group_layout"),
+        });
+        // the equirect and the cube sit at different bindings so one module
+        // can declare both
+        let source_layout = |binding, view_dimension| {
+            device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+                entries: &[
+                    wgpu::BindGroupLayoutEntry {
+                        binding,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Texture {
+                            multisampled: false,
+                            view_dimension,
+                            sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                        },
+                        count: None,
+                    },
+                    wgpu::BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: wgpu::ShaderStages::FRAGMENT,
+                        ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Filtering),
+                        count: None,
+                    },
+                ],
+                label: Some("environment_source_bind_group_layout"),
+            })
+        };
+        let equirect_layout = source_layout(0, wgpu::TextureViewDimension::D2);
+        let cube_layout = source_layout(1, wgpu::TextureViewDimension::Cube);
+
+        let pipeline_layout = |groups: &[&wgpu::BindGroupLayout]| {
+            device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: Some("environment pipeline"),
+                bind_group_layouts: groups,
+                push_constant_ranges: &[],
+            })
+        };
+        let pipeline = |layout: &wgpu::PipelineLayout, fs_entry, format| {
+            pipeline::PipelineBuilder::with_module(&module, format)
+                .label(fs_entry)
+                .layout(layout)
+                .entry_points("vs_main", fs_entry)
+                .cull_mode(None)
+                .build(device)
+        };
+        let equirect_pipeline_layout = pipeline_layout(&[&face_layout, &equirect_layout]);
+        let cube_pipeline_layout = pipeline_layout(&[&face_layout, &cube_layout]);
+        let brdf_pipeline_layout = pipeline_layout(&[&face_layout]);
+        Self {
+            equirect: pipeline(&equirect_pipeline_layout, "fs_equirect", FORMAT),
+            irradiance: pipeline(&cube_pipeline_layout, "fs_irradiance", FORMAT),
+            prefilter: pipeline(&cube_pipeline_layout, "fs_prefilter", FORMAT),
+            brdf: pipeline(&brdf_pipeline_layout, "fs_brdf", wgpu::TextureFormat::Rg16Float),
+            face_layout,
+            equirect_layout,
+            cube_layout,
+        }
+    }
+
+    fn source_group(
+        &self,
+        device: &wgpu::Device,
+        layout: &wgpu::BindGroupLayout,
+        binding: u32,
+        view: &wgpu::TextureView,
+        sampler: &wgpu::Sampler,
+    ) -> wgpu::BindGroup {
+        device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding,
+                    resource: wgpu::BindingResource::TextureView(view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::Sampler(sampler),
+                },
+            ],
+            label: Some("environment_source_bind_group"),
+        })
+    }
+
+    // Runs the passes in order, later ones can read what earlier ones wrote.
+    fn run(&self, device: &wgpu::Device, queue: &wgpu::Queue, passes: &[FilterPass]) {
+        let size = std::mem::size_of::<FaceUniform>() as u32;
+        let stride = device.limits().min_uniform_buffer_offset_alignment.max(size);
+        let mut contents = vec![0; (stride * passes.len() as u32) as usize];
+        for (slot, pass) in passes.iter().enumerate() {
+            let offset = slot * stride as usize;
+            contents[offset..offset + size as usize].copy_from_slice(bytemuck::bytes_of(&pass.uniform));
+        }
+        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("Environment Face Buffer"),
+            contents: &contents,
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+        let face_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.face_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &buffer,
+                    offset: 0,
+                    size: NonZeroU64::new(size as u64),
+                }),
+            }],
+            label: Some("environment_face_bind_group"),
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Environment Encoder"),
+        });
+        for (slot, pass) in passes.iter().enumerate() {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Environment Pass"),
+                color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+                    view: &pass.target,
+                    resolve_target: None,
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color::BLACK),
+                        store: true,
+                    },
+                })],
+                depth_stencil_attachment: None,
+            });
+            render_pass.set_pipeline(pass.pipeline);
+            render_pass.set_bind_group(0, &face_group, &[slot as u32 * stride]);
+            if let Some(source) = pass.source {
+                render_pass.set_bind_group(1, source, &[]);
+            }
+            render_pass.draw(0..3, 0..1);
+        }
+        queue.submit(std::iter::once(encoder.finish()));
+    }
+}
+
+fn create_cube(device: &wgpu::Device, label: &str, size: u32, mips: u32) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some(label),
+        size: wgpu::Extent3d {
+            width: size,
+            height: size,
+            depth_or_array_layers: 6,
+        },
+        mip_level_count: mips,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: FORMAT,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    })
+}
+
+fn cube_view(texture: &wgpu::Texture) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        dimension: Some(wgpu::TextureViewDimension::Cube),
+        ..Default::default()
+    })
+}
+
+// the filter passes render into one face of one mip at a time
+fn face_view(texture: &wgpu::Texture, mip: u32, face: u32) -> wgpu::TextureView {
+    texture.create_view(&wgpu::TextureViewDescriptor {
+        label: Some("environment face"),
+        dimension: Some(wgpu::TextureViewDimension::D2),
+        base_mip_level: mip,
+        mip_level_count: Some(1),
+        base_array_layer: face,
+        array_layer_count: Some(1),
+        ..Default::default()
+    })
+}
+
+fn create_equirect(device: &wgpu::Device, queue: &wgpu::Queue, image: &image::Rgba32FImage) -> wgpu::Texture {
+    let mips = equirect_mips(image);
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("equirect"),
+        size: wgpu::Extent3d {
+            width: image.width(),
+            height: image.height(),
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: mips.len() as u32,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: FORMAT,
+        usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+        view_formats: &[],
+    });
+    for (level, mip) in mips.iter().enumerate() {
+        let half_floats: Vec<u8> = mip.as_raw().iter().flat_map(|&value| f16_bits(value).to_le_bytes()).collect();
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                aspect: wgpu::TextureAspect::All,
+                texture: &texture,
+                mip_level: level as u32,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            &half_floats,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(8 * mip.width()),
+                rows_per_image: Some(mip.height()),
+            },
+            wgpu::Extent3d {
+                width: mip.width(),
+                height: mip.height(),
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+    texture
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn half_floats_round_trip_common_values() {
+        assert_eq!(f16_bits(0.0), 0x0000);
+        assert_eq!(f16_bits(-0.0), 0x8000);
+        assert_eq!(f16_bits(1.0), 0x3c00);
+        assert_eq!(f16_bits(-2.0), 0xc000);
+        assert_eq!(f16_bits(0.5), 0x3800);
+        assert_eq!(f16_bits(65504.0), 0x7bff);
+        // smallest subnormal
+        assert_eq!(f16_bits(5.960_464_5e-8), 0x0001);
+        // too bright for f16 stays finite
+        assert_eq!(f16_bits(1.0e6), 0x7bff);
+        // 1 + 2^-11 is halfway, rounds up to the next step
+        assert_eq!(f16_bits(1.000_488_3), 0x3c01);
+    }
+
+    #[test]
+    fn equirect_mips_reach_one_texel() {
+        let image = image::Rgba32FImage::from_pixel(8, 2, image::Rgba([1.0, 0.5, 0.25, 1.0]));
+        let mips = equirect_mips(&image);
+        let sizes: Vec<_> = mips.iter().map(|mip| mip.dimensions()).collect();
+        assert_eq!(sizes, vec![(8, 2), (4, 1), (2, 1), (1, 1)]);
+        assert!((mips[3].get_pixel(0, 0)[1] - 0.5).abs() < 1e-5);
+        assert_eq!(mip_count(512), 10);
+    }
+
+    #[test]
+    fn sky_looks_where_the_camera_looks() {
+        let view = transforms::create_view((0.0, 5.0, -10.0).into(), (0.0, 0.0, 0.0).into(), Vector3::unit_y());
+        let forward = Vector3::new(0.0, -5.0, 10.0).normalize();
+        let ortho = transforms::Projection::Orthographic { height: 10.0, zoom: 1.0, near: 0.1, far: 100.0 };
+        let reverse_z = transforms::Projection::InfiniteReverseZ { fovy: Deg(60.0).into(), near: 0.1 };
+        for projection in [transforms::Projection::default(), ortho, reverse_z] {
+            let p = sky_matrix(view, &projection, 1.5) * Vector4::new(0.0, 0.0, 0.5, 1.0);
+            let dir = (p.truncate() / p.w).normalize();
+            assert!((dir - forward).magnitude() < 1e-4, "{:?}: {:?}", projection, dir);
+        }
+    }
+}
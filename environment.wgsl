@@ -0,0 +1,180 @@
+// Passes that turn an equirectangular HDR image into the cubemaps used for
+// image-based lighting. Every pass draws one fullscreen triangle into one
+// cube face (or the BRDF lookup table).
+struct Face {
+    // index into the face order of wgpu cubemaps: +X, -X, +Y, -Y, +Z, -Z
+    face: u32,
+    samples: u32,
+    // prefiltered mips only
+    roughness: f32,
+    // mip of the source the pass reads from
+    source_lod: f32,
+    // face size of the first mip of the source cubemap
+    source_size: f32,
+};
+
+@binding(0) @group(0) var<uniform> face: Face;
+
+@binding(0) @group(1) var t_equirect: texture_2d<f32>;
+@binding(1) @group(1) var t_cube: texture_cube<f32>;
+@binding(2) @group(1) var s_source: sampler;
+
+const PI: f32 = 3.14159265;
+
+struct Output {
+    @builtin(position) position: vec4<f32>,
+    // -1..1 from the left and top edge of the target
+    @location(0) uv: vec2<f32>,
+};
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> Output {
+    // (-1, -1), (3, -1), (-1, 3)
+    let x = f32((index & 1u) << 2u) - 1.0;
+    let y = f32((index & 2u) << 1u) - 1.0;
+    var output: Output;
+    output.position = vec4<f32>(x, y, 0.0, 1.0);
+    output.uv = vec2<f32>(x, -y);
+    return output;
+}
+
+// Direction through the texel at `uv` on the current face.
+fn face_direction(uv: vec2<f32>) -> vec3<f32> {
+    switch face.face {
+        case 0u: { return normalize(vec3<f32>(1.0, -uv.y, -uv.x)); }
+        case 1u: { return normalize(vec3<f32>(-1.0, -uv.y, uv.x)); }
+        case 2u: { return normalize(vec3<f32>(uv.x, 1.0, uv.y)); }
+        case 3u: { return normalize(vec3<f32>(uv.x, -1.0, -uv.y)); }
+        case 4u: { return normalize(vec3<f32>(uv.x, -uv.y, 1.0)); }
+        default: { return normalize(vec3<f32>(-uv.x, -uv.y, -1.0)); }
+    }
+}
+
+// Orthonormal basis around `n`, z along `n`.
+fn tangent_frame(n: vec3<f32>) -> mat3x3<f32> {
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if (abs(n.y) > 0.999) {
+        up = vec3<f32>(1.0, 0.0, 0.0);
+    }
+    let tangent = normalize(cross(up, n));
+    return mat3x3<f32>(tangent, cross(n, tangent), n);
+}
+
+@fragment
+fn fs_equirect(in: Output) -> @location(0) vec4<f32> {
+    let dir = face_direction(in.uv);
+    let uv = vec2<f32>(atan2(dir.z, dir.x) / (2.0 * PI) + 0.5, acos(clamp(dir.y, -1.0, 1.0)) / PI);
+    return vec4<f32>(textureSampleLevel(t_equirect, s_source, uv, face.source_lod).rgb, 1.0);
+}
+
+// Cosine weighted average of the hemisphere around the direction, so the
+// shader only multiplies it with the albedo.
+@fragment
+fn fs_irradiance(in: Output) -> @location(0) vec4<f32> {
+    let frame = tangent_frame(face_direction(in.uv));
+    let phi_steps = face.samples;
+    let theta_steps = max(face.samples / 4u, 1u);
+    var sum = vec3<f32>(0.0);
+    var weight = 0.0;
+    for (var i = 0u; i < phi_steps; i = i + 1u) {
+        let phi = (f32(i) + 0.5) / f32(phi_steps) * 2.0 * PI;
+        for (var j = 0u; j < theta_steps; j = j + 1u) {
+            let theta = (f32(j) + 0.5) / f32(theta_steps) * 0.5 * PI;
+            let local = vec3<f32>(sin(theta) * cos(phi), sin(theta) * sin(phi), cos(theta));
+            // cos for the Lambert term, sin for the solid angle of the step
+            let w = cos(theta) * sin(theta);
+            sum = sum + textureSampleLevel(t_cube, s_source, frame * local, face.source_lod).rgb * w;
+            weight = weight + w;
+        }
+    }
+    return vec4<f32>(sum / weight, 1.0);
+}
+
+fn radical_inverse(index: u32) -> f32 {
+    var bits = index;
+    var result = 0u;
+    for (var i = 0u; i < 32u; i = i + 1u) {
+        result = (result << 1u) | (bits & 1u);
+        bits = bits >> 1u;
+    }
+    return f32(result) * 2.3283064e-10;
+}
+
+fn hammersley(i: u32, count: u32) -> vec2<f32> {
+    return vec2<f32>(f32(i) / f32(count), radical_inverse(i));
+}
+
+// Half vector around +z distributed like the GGX lobe.
+fn importance_sample_ggx(xi: vec2<f32>, roughness: f32) -> vec3<f32> {
+    let a = roughness * roughness;
+    let phi = 2.0 * PI * xi.x;
+    let cos_theta = sqrt((1.0 - xi.y) / (1.0 + (a * a - 1.0) * xi.y));
+    let sin_theta = sqrt(1.0 - cos_theta * cos_theta);
+    return vec3<f32>(sin_theta * cos(phi), sin_theta * sin(phi), cos_theta);
+}
+
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / max(PI * d * d, 0.0001);
+}
+
+// GGX convolution for one roughness, assuming the view direction equals the
+// normal. Samples read lower source mips where they are spread out, which
+// keeps bright spots in the source from turning into speckles.
+@fragment
+fn fs_prefilter(in: Output) -> @location(0) vec4<f32> {
+    let n = face_direction(in.uv);
+    if (face.roughness == 0.0) {
+        return vec4<f32>(textureSampleLevel(t_cube, s_source, n, face.source_lod).rgb, 1.0);
+    }
+    let frame = tangent_frame(n);
+    let texel_solid_angle = 4.0 * PI / (6.0 * face.source_size * face.source_size);
+    var sum = vec3<f32>(0.0);
+    var weight = 0.0;
+    for (var i = 0u; i < face.samples; i = i + 1u) {
+        let h = frame * importance_sample_ggx(hammersley(i, face.samples), face.roughness);
+        let l = 2.0 * dot(n, h) * h - n;
+        let n_dot_l = dot(n, l);
+        if (n_dot_l > 0.0) {
+            let n_dot_h = max(dot(n, h), 0.0);
+            // with n == v the pdf of l is D / 4
+            let pdf = distribution_ggx(n_dot_h, face.roughness) / 4.0 + 0.0001;
+            let sample_solid_angle = 1.0 / (f32(face.samples) * pdf);
+            let lod = max(0.5 * log2(sample_solid_angle / texel_solid_angle) + 1.0, 0.0);
+            sum = sum + textureSampleLevel(t_cube, s_source, l, lod).rgb * n_dot_l;
+            weight = weight + n_dot_l;
+        }
+    }
+    return vec4<f32>(sum / max(weight, 0.0001), 1.0);
+}
+
+// Scale and bias to F0 of the split-sum approximation, by n.v along x and
+// roughness along y.
+@fragment
+fn fs_brdf(in: Output) -> @location(0) vec4<f32> {
+    let uv = in.uv * 0.5 + 0.5;
+    let n_dot_v = max(uv.x, 0.001);
+    let roughness = uv.y;
+    let v = vec3<f32>(sqrt(1.0 - n_dot_v * n_dot_v), 0.0, n_dot_v);
+    // Smith-Schlick with k = a / 2 for image-based lighting
+    let k = roughness * roughness / 2.0;
+    var scale = 0.0;
+    var bias = 0.0;
+    for (var i = 0u; i < face.samples; i = i + 1u) {
+        let h = importance_sample_ggx(hammersley(i, face.samples), roughness);
+        let l = 2.0 * dot(v, h) * h - v;
+        let n_dot_l = max(l.z, 0.0);
+        if (n_dot_l > 0.0) {
+            let n_dot_h = max(h.z, 0.0);
+            let v_dot_h = max(dot(v, h), 0.0);
+            let g = n_dot_v / (n_dot_v * (1.0 - k) + k) * n_dot_l / (n_dot_l * (1.0 - k) + k);
+            let g_vis = g * v_dot_h / (n_dot_h * n_dot_v);
+            let fc = pow(1.0 - v_dot_h, 5.0);
+            scale = scale + (1.0 - fc) * g_vis;
+            bias = bias + fc * g_vis;
+        }
+    }
+    return vec4<f32>(scale / f32(face.samples), bias / f32(face.samples), 0.0, 1.0);
+}
@@ -0,0 +1,287 @@
+// Golden-image regression tests: canned scenes are rendered headlessly and
+// compared against the reference PNGs checked in under golden/.
+//
+// Run with UPDATE_GOLDEN=1 to (re)write the references after an intended
+// visual change. Failing scenes leave <name>.actual.png and <name>.diff.png
+// in golden/failures/.
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use cgmath::prelude::*;
+use image::{Rgba, RgbaImage};
+
+use crate::{common, instancing, lights, model, resources, transforms};
+
+const WIDTH: u32 = 320;
+const HEIGHT: u32 = 240;
+// largest per-channel difference that still counts as a matching pixel
+const CHANNEL_TOLERANCE: u8 = 3;
+// share of pixels allowed to exceed CHANNEL_TOLERANCE
+const MAX_MISMATCH_RATIO: f32 = 0.001;
+
+// some drivers don't like several devices being created at once, other
+// tests that need a device take this too
+pub(crate) static GPU: Mutex<()> = Mutex::new(());
+
+fn golden_dir() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("golden")
+}
+
+fn render_scene(setup: impl FnOnce(&mut common::State)) -> RgbaImage {
+    let _guard = GPU.lock().unwrap_or_else(|e| e.into_inner());
+    let mut state = pollster::block_on(common::State::new_headless(WIDTH, HEIGHT, &transforms::InitConfig::headless())).unwrap();
+    setup(&mut state);
+    state.update(std::time::Duration::ZERO);
+    state.render().unwrap();
+    state.render_image().unwrap()
+}
+
+// Returns the number of mismatched pixels and an image marking them in red
+// over a dimmed copy of the expected frame.
+fn compare(expected: &RgbaImage, actual: &RgbaImage) -> (usize, RgbaImage) {
+    let mut mismatched = 0;
+    let mut diff = RgbaImage::new(expected.width(), expected.height());
+    for (x, y, e) in expected.enumerate_pixels() {
+        let a = actual.get_pixel(x, y);
+        let exceeds = e.0.iter().zip(a.0.iter()).any(|(e, a)| e.abs_diff(*a) > CHANNEL_TOLERANCE);
+        let pixel = if exceeds {
+            mismatched += 1;
+            Rgba([255, 0, 0, 255])
+        } else {
+            let luma = (e[0] as u32 + e[1] as u32 + e[2] as u32) / 3 / 3;
+            Rgba([luma as u8, luma as u8, luma as u8, 255])
+        };
+        diff.put_pixel(x, y, pixel);
+    }
+    (mismatched, diff)
+}
+
+fn check_golden(name: &str, actual: RgbaImage) {
+    let reference = golden_dir().join(format!("{}.png", name));
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        actual.save(&reference).unwrap();
+        return;
+    }
+
+    let expected = image::open(&reference)
+        .unwrap_or_else(|e| panic!("missing reference {:?} ({}), run with UPDATE_GOLDEN=1", reference, e))
+        .to_rgba8();
+    assert_eq!(expected.dimensions(), actual.dimensions(), "{}: frame size changed", name);
+
+    let (mismatched, diff) = compare(&expected, &actual);
+    let allowed = (MAX_MISMATCH_RATIO * (WIDTH * HEIGHT) as f32) as usize;
+    if mismatched > allowed {
+        let failures = golden_dir().join("failures");
+        std::fs::create_dir_all(&failures).unwrap();
+        actual.save(failures.join(format!("{}.actual.png", name))).unwrap();
+        diff.save(failures.join(format!("{}.diff.png", name))).unwrap();
+        panic!(
+            "{}: {} pixels differ from {:?} (allowed {}), see {:?}",
+            name, mismatched, reference, allowed, failures
+        );
+    }
+}
+
+#[test]
+fn instanced_cube_grid() {
+    let image = render_scene(|state| state.set_instances(instancing::craete_instances()));
+    check_golden("instanced_cube_grid", image);
+}
+
+fn textured_cube_scene(state: &mut common::State) {
+    state.set_instances(vec![instancing::Instance::new(
+        cgmath::Vector3::zero(),
+        cgmath::Quaternion::from_angle_y(cgmath::Deg(30.0)),
+    )]);
+    state.set_lights(vec![lights::Light::point((2.0, 2.0, -2.0).into(), 20.0, [1.0, 1.0, 1.0], 4.0)]);
+}
+
+#[test]
+fn textured_cube() {
+    let image = render_scene(textured_cube_scene);
+    check_golden("textured_cube", image);
+}
+
+// Reverse-Z only changes depth precision, the frame must look the same.
+#[test]
+fn reverse_z_matches_standard_depth() {
+    let standard = render_scene(textured_cube_scene);
+    let reverse_z = render_scene(|state| {
+        state.set_depth_mode(transforms::DepthMode::ReverseZ);
+        textured_cube_scene(state);
+    });
+    let (mismatched, _) = compare(&standard, &reverse_z);
+    assert!(mismatched <= (MAX_MISMATCH_RATIO * (WIDTH * HEIGHT) as f32) as usize, "{} pixels differ", mismatched);
+}
+
+#[test]
+fn reverse_z_keeps_orthographic_view() {
+    let ortho = |state: &mut common::State| {
+        textured_cube_scene(state);
+        state.set_projection(transforms::Projection::Orthographic { height: 6.0, zoom: 1.0, near: 0.1, far: 100.0 });
+    };
+    let standard = render_scene(ortho);
+    let reverse_z = render_scene(|state| {
+        ortho(state);
+        state.set_depth_mode(transforms::DepthMode::ReverseZ);
+    });
+    let (mismatched, _) = compare(&standard, &reverse_z);
+    assert!(mismatched <= (MAX_MISMATCH_RATIO * (WIDTH * HEIGHT) as f32) as usize, "{} pixels differ", mismatched);
+}
+
+// instances set after the last render() still show up in the capture
+#[test]
+fn render_image_uploads_pending_instances() {
+    let expected = render_scene(textured_cube_scene);
+    let _guard = GPU.lock().unwrap_or_else(|e| e.into_inner());
+    let mut state = pollster::block_on(common::State::new_headless(WIDTH, HEIGHT, &transforms::InitConfig::headless())).unwrap();
+    state.update(std::time::Duration::ZERO);
+    state.render().unwrap();
+    textured_cube_scene(&mut state);
+    state.update(std::time::Duration::ZERO);
+    let (mismatched, _) = compare(&expected, &state.render_image().unwrap());
+    assert!(mismatched <= (MAX_MISMATCH_RATIO * (WIDTH * HEIGHT) as f32) as usize, "{} pixels differ", mismatched);
+}
+
+#[test]
+fn textured_cube_msaa() {
+    let mut supported = true;
+    let image = render_scene(|state| {
+        supported = state.set_sample_count(4).is_ok();
+        textured_cube_scene(state);
+    });
+    if !supported {
+        eprintln!("skipping textured_cube_msaa: 4x MSAA not supported");
+        return;
+    }
+    check_golden("textured_cube_msaa", image);
+}
+
+#[test]
+fn metallic_roughness_cube() {
+    let image = render_scene(|state| {
+        textured_cube_scene(state);
+        state.edit_materials(|materials| {
+            materials[0].shading_model = model::ShadingModel::MetallicRoughness;
+            materials[0].uniform.metallic = 0.7;
+            materials[0].uniform.roughness = 0.35;
+        });
+    });
+    check_golden("metallic_roughness_cube", image);
+}
+
+// only image-based light, the sky shows behind the cubes and in their
+// reflections
+#[test]
+fn environment_lighting() {
+    let sky = pollster::block_on(resources::load_hdr("sky.hdr")).unwrap();
+    let image = render_scene(|state| {
+        state.set_instances(instancing::grid_layout(&[3, 1], &[3.0, 3.0]));
+        state.set_lights(Vec::new());
+        state.set_environment(&sky);
+        state.edit_materials(|materials| {
+            materials[0].shading_model = model::ShadingModel::MetallicRoughness;
+            materials[0].uniform.metallic = 1.0;
+            materials[0].uniform.roughness = 0.3;
+        });
+    });
+    check_golden("environment_lighting", image);
+}
+
+// glTF meshes are placed by their node hierarchy at every instance
+#[test]
+fn gltf_node_hierarchy() {
+    let image = render_scene(|state| {
+        pollster::block_on(state.set_model("gltf-hierarchy.gltf")).unwrap();
+        // the triangles face +z, the camera looks from -z
+        let facing = cgmath::Quaternion::from_angle_y(cgmath::Deg(180.0));
+        state.set_instances(vec![
+            instancing::Instance::new((-3.0, 0.0, 0.0).into(), facing),
+            instancing::Instance::new((3.0, -2.0, 0.0).into(), facing),
+        ]);
+    });
+    check_golden("gltf_node_hierarchy", image);
+}
+
+#[test]
+fn mixed_lights() {
+    let image = render_scene(|state| {
+        state.set_instances(instancing::grid_layout(&[3, 3], &[2.5, 2.5]));
+        state.set_ambient([0.2, 0.2, 0.2]);
+        state.set_lights(vec![
+            lights::Light::point((-3.0, 1.5, -1.0).into(), 6.0, [1.0, 0.2, 0.2], 6.0),
+            lights::Light::spot(
+                (2.5, 4.0, 0.0).into(),
+                (0.0, -1.0, 0.0).into(),
+                10.0,
+                cgmath::Deg(15.0).into(),
+                cgmath::Deg(30.0).into(),
+                [0.2, 1.0, 0.2],
+                16.0,
+            ),
+            lights::Light::directional((0.5, -1.0, 1.0).into(), [0.2, 0.2, 1.0], 0.4),
+        ]);
+    });
+    check_golden("mixed_lights", image);
+}
+
+// cubes standing on a flattened cube, lit by a low sun and a spot light
+#[test]
+fn shadowed_grid() {
+    let image = render_scene(|state| {
+        let mut instances = instancing::grid_layout(&[4, 4], &[3.0, 3.0]);
+        let mut ground = instancing::Instance::new(cgmath::Vector3::new(0.0, -1.5, 0.0), cgmath::Quaternion::one());
+        ground.scale = cgmath::Vector3::new(12.0, 0.25, 12.0);
+        instances.push(ground);
+        state.set_instances(instances);
+        state.set_ambient([0.4, 0.4, 0.4]);
+        state.set_lights(vec![
+            lights::Light::directional((1.0, -0.8, 0.6).into(), [1.0, 1.0, 1.0], 1.0),
+            lights::Light::spot(
+                (-3.0, 5.0, -3.0).into(),
+                (0.3, -1.0, 0.3).into(),
+                12.0,
+                cgmath::Deg(20.0).into(),
+                cgmath::Deg(35.0).into(),
+                [1.0, 0.6, 0.3],
+                30.0,
+            ),
+        ]);
+    });
+    check_golden("shadowed_grid", image);
+}
+
+// a point light between cubes on a flattened cube, shadows fall outwards
+#[test]
+fn point_light_shadows() {
+    let image = render_scene(|state| {
+        let mut instances = instancing::ring_layout(6, 3.0);
+        let mut ground = instancing::Instance::new(cgmath::Vector3::new(0.0, -1.5, 0.0), cgmath::Quaternion::one());
+        ground.scale = cgmath::Vector3::new(12.0, 0.25, 12.0);
+        instances.push(ground);
+        state.set_instances(instances);
+        state.set_ambient([0.4, 0.4, 0.4]);
+        state.set_lights(vec![lights::Light::point((0.0, 1.5, 0.0).into(), 20.0, [1.0, 0.9, 0.7], 12.0)]);
+    });
+    check_golden("point_light_shadows", image);
+}
+
+#[test]
+fn light_marker() {
+    let image = render_scene(|state| {
+        state.set_instances(Vec::new());
+        state.set_lights(vec![lights::Light::point((1.0, 1.0, 0.0).into(), 20.0, [1.0, 1.0, 1.0], 4.0)]);
+    });
+    check_golden("light_marker", image);
+}
+
+#[test]
+fn compare_flags_pixels_over_tolerance() {
+    let expected = RgbaImage::from_pixel(4, 4, Rgba([100, 100, 100, 255]));
+    let mut actual = expected.clone();
+    actual.put_pixel(0, 0, Rgba([100 + CHANNEL_TOLERANCE, 100, 100, 255]));
+    actual.put_pixel(1, 0, Rgba([100, 100 + CHANNEL_TOLERANCE + 1, 100, 255]));
+    let (mismatched, diff) = compare(&expected, &actual);
+    assert_eq!(mismatched, 1);
+    assert_eq!(*diff.get_pixel(1, 0), Rgba([255, 0, 0, 255]));
+}
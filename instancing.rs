@@ -4,43 +4,437 @@ use cgmath::prelude::*;
 pub struct Instance {
     pub position: cgmath::Vector3<f32>,
     pub rotation: cgmath::Quaternion<f32>,
+    pub scale: cgmath::Vector3<f32>,
+    // multiplied with the material color
+    pub color: [f32; 4],
 }
 
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct InstanceRaw {
     model: [[f32; 4]; 4],
+    normal: [[f32; 3]; 3],
+    color: [f32; 4],
 }
 
 impl Instance {
-    pub fn to_raw(&self) -> InstanceRaw {
+    pub fn new(position: cgmath::Vector3<f32>, rotation: cgmath::Quaternion<f32>) -> Self {
+        Self {
+            position,
+            rotation,
+            scale: cgmath::Vector3::new(1.0, 1.0, 1.0),
+            color: [1.0, 1.0, 1.0, 1.0],
+        }
+    }
+
+    // `node` is the model-space transform of the model node being drawn.
+    pub fn to_raw(&self, node: cgmath::Matrix4<f32>) -> InstanceRaw {
+        let scale = cgmath::Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z);
+        // inverse transpose of rotation * scale, which is rotation * scale^-1;
+        // an axis flattened to zero keeps its unscaled normal instead of NaN
+        let inverse = |s: f32| if s.abs() > f32::EPSILON { 1.0 / s } else { 1.0 };
+        let inv_scale = cgmath::Matrix3::from_diagonal(cgmath::Vector3::new(
+            inverse(self.scale.x),
+            inverse(self.scale.y),
+            inverse(self.scale.z),
+        ));
+        let node_linear = cgmath::Matrix3::from_cols(node.x.truncate(), node.y.truncate(), node.z.truncate());
+        let node_normal = node_linear.invert().unwrap_or(node_linear).transpose();
         InstanceRaw {
-            model: (cgmath::Matrix4::from_translation(self.position) * cgmath::Matrix4::from(self.rotation)).into(),
+            model: (cgmath::Matrix4::from_translation(self.position) * cgmath::Matrix4::from(self.rotation) * scale * node).into(),
+            normal: (cgmath::Matrix3::from(self.rotation) * inv_scale * node_normal).into(),
+            color: self.color,
+        }
+    }
+}
+
+// Stable reference to an instance in an InstanceSet. The generation makes
+// handles of removed instances stop resolving once their slot is reused.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct InstanceHandle {
+    slot: u32,
+    generation: u32,
+}
+
+struct Slot {
+    generation: u32,
+    // position in InstanceSet::instances, None while the slot is free
+    index: Option<usize>,
+}
+
+// Instances that can be added, removed and changed at runtime. They are kept
+// densely packed in GPU order; only ranges that changed since the last
+// upload are written, and the GPU buffer grows geometrically.
+//
+// The buffer holds one block of all instances per model node, each block
+// with that node's transform applied; node_range gives the block to draw a
+// mesh with.
+pub struct InstanceSet {
+    instances: Vec<Instance>,
+    nodes: Vec<cgmath::Matrix4<f32>>,
+    // instance count the buffer's blocks were laid out for
+    uploaded_len: usize,
+    // slot of the handle owning each entry of `instances`
+    owners: Vec<u32>,
+    slots: Vec<Slot>,
+    free_slots: Vec<u32>,
+    dirty: Vec<std::ops::Range<usize>>,
+    buffer: Option<wgpu::Buffer>,
+    capacity: usize,
+}
+
+const MIN_INSTANCE_CAPACITY: usize = 16;
+
+fn grow_capacity(capacity: usize, needed: usize) -> usize {
+    let mut capacity = capacity.max(MIN_INSTANCE_CAPACITY);
+    while capacity < needed {
+        capacity *= 2;
+    }
+    capacity
+}
+
+impl InstanceSet {
+    pub fn new() -> Self {
+        Self {
+            instances: Vec::new(),
+            nodes: vec![cgmath::Matrix4::identity()],
+            uploaded_len: 0,
+            owners: Vec::new(),
+            slots: Vec::new(),
+            free_slots: Vec::new(),
+            dirty: Vec::new(),
+            buffer: None,
+            capacity: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.instances.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.instances.is_empty()
+    }
+
+    pub fn add(&mut self, instance: Instance) -> InstanceHandle {
+        let index = self.instances.len();
+        let slot = match self.free_slots.pop() {
+            Some(slot) => {
+                self.slots[slot as usize].index = Some(index);
+                slot
+            }
+            None => {
+                self.slots.push(Slot { generation: 0, index: Some(index) });
+                self.slots.len() as u32 - 1
+            }
+        };
+        self.instances.push(instance);
+        self.owners.push(slot);
+        self.mark_dirty(index..index + 1);
+        InstanceHandle {
+            slot,
+            generation: self.slots[slot as usize].generation,
+        }
+    }
+
+    // The last instance moves into the freed place, so at most one other
+    // entry has to be re-uploaded.
+    pub fn remove(&mut self, handle: InstanceHandle) -> Option<Instance> {
+        let index = self.index_of(handle)?;
+        let slot = &mut self.slots[handle.slot as usize];
+        slot.index = None;
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free_slots.push(handle.slot);
+
+        let instance = self.instances.swap_remove(index);
+        self.owners.swap_remove(index);
+        if index < self.instances.len() {
+            self.slots[self.owners[index] as usize].index = Some(index);
+            self.mark_dirty(index..index + 1);
+        }
+        Some(instance)
+    }
+
+    #[cfg(test)]
+    pub fn get(&self, handle: InstanceHandle) -> Option<&Instance> {
+        self.index_of(handle).map(|index| &self.instances[index])
+    }
+
+    // Marks the instance for upload whether or not it is actually changed.
+    #[cfg(test)]
+    pub fn get_mut(&mut self, handle: InstanceHandle) -> Option<&mut Instance> {
+        let index = self.index_of(handle)?;
+        self.mark_dirty(index..index + 1);
+        Some(&mut self.instances[index])
+    }
+
+    pub fn clear(&mut self) {
+        let handles = self
+            .owners
+            .iter()
+            .map(|&slot| InstanceHandle {
+                slot,
+                generation: self.slots[slot as usize].generation,
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            self.remove(handle);
+        }
+        self.dirty.clear();
+    }
+
+    // World transforms of the model's nodes; re-uploads everything when they
+    // change.
+    pub fn set_nodes(&mut self, nodes: Vec<cgmath::Matrix4<f32>>) {
+        assert!(!nodes.is_empty(), "models have at least one node");
+        if nodes != self.nodes {
+            self.nodes = nodes;
+            self.mark_dirty(0..self.instances.len());
+        }
+    }
+
+    // Instances to draw a mesh of the given node with.
+    pub fn node_range(&self, node: usize) -> std::ops::Range<u32> {
+        let start = (node * self.len()) as u32;
+        start..start + self.len() as u32
+    }
+
+    fn index_of(&self, handle: InstanceHandle) -> Option<usize> {
+        let slot = self.slots.get(handle.slot as usize)?;
+        if slot.generation != handle.generation {
+            return None;
+        }
+        slot.index
+    }
+
+    fn mark_dirty(&mut self, range: std::ops::Range<usize>) {
+        if !range.is_empty() {
+            self.dirty.push(range);
+        }
+    }
+
+    // Sorted, merged dirty ranges clipped to the live instances; clears them.
+    fn take_dirty(&mut self) -> Vec<std::ops::Range<usize>> {
+        let len = self.instances.len();
+        let mut ranges = std::mem::take(&mut self.dirty)
+            .into_iter()
+            .map(|r| r.start.min(len)..r.end.min(len))
+            .filter(|r| !r.is_empty())
+            .collect::<Vec<_>>();
+        ranges.sort_by_key(|r| r.start);
+        let mut merged: Vec<std::ops::Range<usize>> = Vec::new();
+        for range in ranges {
+            match merged.last_mut() {
+                Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+                _ => merged.push(range),
+            }
+        }
+        merged
+    }
+
+    // Writes changed instances to the GPU, reallocating the buffer first if
+    // the set has outgrown it.
+    pub fn upload(&mut self, device: &wgpu::Device, queue: &wgpu::Queue) {
+        let needed = self.len() * self.nodes.len();
+        if self.buffer.is_none() || needed > self.capacity {
+            self.capacity = grow_capacity(self.capacity, needed);
+            self.buffer = Some(device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some("Instance Buffer"),
+                size: (self.capacity * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress,
+                usage: wgpu::BufferUsages::VERTEX | wgpu::BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }));
+            self.dirty.clear();
+            self.dirty.push(0..self.instances.len());
+        }
+        // blocks after the first start elsewhere once the count changes
+        if self.nodes.len() > 1 && self.uploaded_len != self.instances.len() {
+            self.mark_dirty(0..self.instances.len());
+        }
+        self.uploaded_len = self.instances.len();
+        let dirty = self.take_dirty();
+        let buffer = self.buffer.as_ref().unwrap();
+        for (block, node) in self.nodes.iter().enumerate() {
+            for range in &dirty {
+                let data = self.instances[range.clone()].iter().map(|i| i.to_raw(*node)).collect::<Vec<_>>();
+                let offset = ((block * self.instances.len() + range.start) * std::mem::size_of::<InstanceRaw>()) as wgpu::BufferAddress;
+                queue.write_buffer(buffer, offset, bytemuck::cast_slice(&data));
+            }
+        }
+    }
+
+    pub fn buffer(&self) -> Option<&wgpu::Buffer> {
+        self.buffer.as_ref()
+    }
+}
+
+impl Default for InstanceSet {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FromIterator<Instance> for InstanceSet {
+    fn from_iter<I: IntoIterator<Item = Instance>>(iter: I) -> Self {
+        let mut set = InstanceSet::new();
+        for instance in iter {
+            set.add(instance);
         }
+        set
     }
 }
 
 pub fn craete_instances() -> Vec<Instance> {
     const NUM_INSTANCES_PER_ROW: u32 = 10;
     const DISTANCE: f32 = 5.0;
-    const INSTANCE_DISPLACEMENT: cgmath::Vector3<f32> = cgmath::Vector3::new(NUM_INSTANCES_PER_ROW as f32 * 0.5, 0.0, NUM_INSTANCES_PER_ROW as f32 * 0.5);
-    (0..NUM_INSTANCES_PER_ROW).flat_map(|z| {
-        (0..NUM_INSTANCES_PER_ROW).map(move |x| {
-            let position = cgmath::Vector3 { x: x as f32 * DISTANCE, y: 0.0, z: z as f32 * DISTANCE } - INSTANCE_DISPLACEMENT;
-
-            let rotation = if position.is_zero() {
-                // this is needed so an object at (0, 0, 0) won't get scaled to zero
-                // as Quaternions can effect scale if they're not created correctly
-                cgmath::Quaternion::from_axis_angle(cgmath::Vector3::unit_z(), cgmath::Deg(0.0))
-            } else {
-                cgmath::Quaternion::from_axis_angle(position.normalize(), cgmath::Deg(45.0))
-            };
+    // grid_layout centers the grid, the scene's grid starts one step before the origin
+    const INSTANCE_DISPLACEMENT: cgmath::Vector3<f32> = cgmath::Vector3::new(
+        (NUM_INSTANCES_PER_ROW - 1) as f32 * DISTANCE * 0.5 - DISTANCE,
+        0.0,
+        (NUM_INSTANCES_PER_ROW - 1) as f32 * DISTANCE * 0.5 - DISTANCE,
+    );
+    let mut instances = grid_layout(&[NUM_INSTANCES_PER_ROW; 2], &[DISTANCE; 2]);
+    for instance in &mut instances {
+        instance.position += INSTANCE_DISPLACEMENT;
+        // an axis of zero length would turn the quaternion into a scale of zero
+        if !instance.position.is_zero() {
+            instance.rotation = cgmath::Quaternion::from_axis_angle(instance.position.normalize(), cgmath::Deg(45.0));
+        }
+    }
+    instances
+}
 
-            Instance {
-                position, rotation,
+// Layout generators for stress scenes and demos. All of them are centered
+// on the origin unless bounds are given explicitly.
+
+// counts.len() axes of instances along x, z, then y, `spacing` apart. No
+// axes give no instances.
+pub fn grid_layout(counts: &[u32], spacing: &[f32]) -> Vec<Instance> {
+    assert!(counts.len() <= 3 && counts.len() == spacing.len(), "grids have up to three axes, each with a spacing");
+    if counts.is_empty() {
+        return Vec::new();
+    }
+    let axes = [cgmath::Vector3::unit_x(), cgmath::Vector3::unit_z(), cgmath::Vector3::unit_y()];
+    let total = counts.iter().product::<u32>();
+    (0..total)
+        .map(|mut i| {
+            let mut position = cgmath::Vector3::zero();
+            for (axis, (&count, &step)) in counts.iter().zip(spacing).enumerate() {
+                let coord = i % count;
+                i /= count;
+                let offset = (coord as f32 - (count - 1) as f32 * 0.5) * step;
+                position += axes[axis] * offset;
             }
+            Instance::new(position, cgmath::Quaternion::one())
+        })
+        .collect()
+}
+
+// evenly spaced on a circle in the xz plane, each turned to face outwards
+pub fn ring_layout(count: u32, radius: f32) -> Vec<Instance> {
+    (0..count)
+        .map(|i| {
+            let angle = cgmath::Rad(std::f32::consts::TAU * i as f32 / count as f32);
+            let position = cgmath::Vector3::new(angle.0.cos(), 0.0, angle.0.sin()) * radius;
+            let rotation = cgmath::Quaternion::from_angle_y(-angle);
+            Instance::new(position, rotation)
+        })
+        .collect()
+}
+
+// Archimedean spiral from the center out to `radius` over `turns`
+// revolutions, climbing from y = 0 to `height`.
+pub fn spiral_layout(count: u32, turns: f32, radius: f32, height: f32) -> Vec<Instance> {
+    let last = count.saturating_sub(1).max(1) as f32;
+    (0..count)
+        .map(|i| {
+            let t = i as f32 / last;
+            let angle = cgmath::Rad(std::f32::consts::TAU * turns * t);
+            let position = cgmath::Vector3::new(angle.0.cos() * radius * t, height * t, angle.0.sin() * radius * t);
+            Instance::new(position, cgmath::Quaternion::from_angle_y(-angle))
+        })
+        .collect()
+}
+
+// Random positions inside [min, max] that are at least `min_distance`
+// apart (Poisson disk sampling by dart throwing on a hash grid). Returns
+// fewer than `count` instances when the box can't fit them, and none when
+// min exceeds max on some axis. The same seed always gives the same layout.
+pub fn scatter_layout(
+    count: usize,
+    min: cgmath::Vector3<f32>,
+    max: cgmath::Vector3<f32>,
+    min_distance: f32,
+    seed: u64,
+) -> Vec<Instance> {
+    use rand::{Rng, SeedableRng};
+    use std::collections::HashMap;
+
+    const ATTEMPTS_PER_INSTANCE: usize = 30;
+    if !(min.x <= max.x && min.y <= max.y && min.z <= max.z) {
+        return Vec::new();
+    }
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    let cell_size = min_distance.max(f32::EPSILON);
+    let cell = |p: cgmath::Vector3<f32>| {
+        let c = (p - min) / cell_size;
+        (c.x.floor() as i32, c.y.floor() as i32, c.z.floor() as i32)
+    };
+    let mut cells: HashMap<(i32, i32, i32), Vec<cgmath::Vector3<f32>>> = HashMap::new();
+    let mut positions = Vec::with_capacity(count);
+
+    for _ in 0..count * ATTEMPTS_PER_INSTANCE {
+        if positions.len() == count {
+            break;
+        }
+        let p = cgmath::Vector3::new(
+            rng.gen_range(min.x..=max.x),
+            rng.gen_range(min.y..=max.y),
+            rng.gen_range(min.z..=max.z),
+        );
+        let (cx, cy, cz) = cell(p);
+        let too_close = (-1..=1).any(|dx| {
+            (-1..=1).any(|dy| {
+                (-1..=1).any(|dz| {
+                    cells
+                        .get(&(cx + dx, cy + dy, cz + dz))
+                        .is_some_and(|ps| ps.iter().any(|q| (p - q).magnitude2() < min_distance * min_distance))
+                })
+            })
+        });
+        if !too_close {
+            cells.entry((cx, cy, cz)).or_default().push(p);
+            positions.push(p);
+        }
+    }
+
+    positions
+        .into_iter()
+        .map(|position| {
+            let angle = cgmath::Deg(rng.gen_range(0.0..360.0));
+            Instance::new(position, cgmath::Quaternion::from_angle_y(angle))
         })
-    }).collect::<Vec<_>>()
+        .collect()
+}
+
+// One instance per distinct vertex position of a mesh, with +y turned
+// along the vertex normal. See resources::load_vertices.
+pub fn mesh_vertex_layout(vertices: &[crate::model::ModelVertex]) -> Vec<Instance> {
+    let mut seen = std::collections::HashSet::new();
+    vertices
+        .iter()
+        // OBJ/glTF split vertices at UV seams, don't stack instances there
+        .filter(|v| seen.insert(v.position.map(f32::to_bits)))
+        .map(|v| {
+            let normal = cgmath::Vector3::from(v.normal);
+            let rotation = if normal.magnitude2() > 0.0 {
+                cgmath::Quaternion::from_arc(cgmath::Vector3::unit_y(), normal.normalize(), None)
+            } else {
+                cgmath::Quaternion::one()
+            };
+            Instance::new(v.position.into(), rotation)
+        })
+        .collect()
 }
 
 impl InstanceRaw {
@@ -77,7 +471,212 @@ impl InstanceRaw {
                     shader_location: 8,
                     format: wgpu::VertexFormat::Float32x4,
                 },
+                // The normal matrix is a mat3, one vec3 per column
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 16]>() as wgpu::BufferAddress,
+                    shader_location: 9,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 19]>() as wgpu::BufferAddress,
+                    shader_location: 10,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 22]>() as wgpu::BufferAddress,
+                    shader_location: 11,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 25]>() as wgpu::BufferAddress,
+                    shader_location: 12,
+                    format: wgpu::VertexFormat::Float32x4,
+                },
             ],
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(x: f32) -> Instance {
+        Instance::new(cgmath::Vector3::new(x, 0.0, 0.0), cgmath::Quaternion::one())
+    }
+
+    #[test]
+    fn handles_survive_removal_of_others() {
+        let mut set = InstanceSet::new();
+        let a = set.add(at(1.0));
+        let b = set.add(at(2.0));
+        let c = set.add(at(3.0));
+        assert_eq!(set.remove(a).map(|i| i.position.x), Some(1.0));
+        assert_eq!(set.len(), 2);
+        assert!(set.get(a).is_none());
+        assert_eq!(set.get(b).map(|i| i.position.x), Some(2.0));
+        assert_eq!(set.get(c).map(|i| i.position.x), Some(3.0));
+    }
+
+    #[test]
+    fn stale_handle_does_not_resolve_after_slot_reuse() {
+        let mut set = InstanceSet::new();
+        let a = set.add(at(1.0));
+        set.remove(a);
+        let b = set.add(at(2.0));
+        assert!(set.get(a).is_none());
+        assert!(set.remove(a).is_none());
+        assert_eq!(set.get(b).map(|i| i.position.x), Some(2.0));
+    }
+
+    #[test]
+    fn dirty_ranges_are_merged() {
+        let mut set = (0..8).map(|i| at(i as f32)).collect::<InstanceSet>();
+        assert_eq!(set.take_dirty(), vec![0..8]);
+
+        let handles = set.owners.iter().map(|&slot| InstanceHandle { slot, generation: 0 }).collect::<Vec<_>>();
+        set.get_mut(handles[5]).unwrap().position.y = 1.0;
+        set.get_mut(handles[1]).unwrap().position.y = 1.0;
+        set.get_mut(handles[2]).unwrap().position.y = 1.0;
+        assert_eq!(set.take_dirty(), vec![1..3, 5..6]);
+        assert!(set.take_dirty().is_empty());
+
+        // removing from the middle only dirties the slot the last one moved into
+        set.remove(handles[3]);
+        assert_eq!(set.take_dirty(), vec![3..4]);
+        // removing the last one moves nothing
+        set.remove(handles[6]);
+        assert!(set.take_dirty().is_empty());
+    }
+
+    #[test]
+    fn zero_scale_keeps_normals_finite() {
+        let mut instance = at(0.0);
+        instance.scale = cgmath::Vector3::new(0.0, 1.0, 2.0);
+        let raw = instance.to_raw(cgmath::Matrix4::identity());
+        assert!(raw.normal.iter().flatten().all(|v| v.is_finite()), "{:?}", raw.normal);
+        assert_eq!(raw.normal[0], [1.0, 0.0, 0.0]);
+        assert_eq!(raw.normal[2], [0.0, 0.0, 0.5]);
+    }
+
+    #[test]
+    fn nodes_are_placed_at_each_instance() {
+        let mut instance = at(1.0);
+        instance.scale = cgmath::Vector3::new(2.0, 2.0, 2.0);
+        let node = cgmath::Matrix4::from_translation(cgmath::Vector3::new(0.0, 3.0, 0.0));
+        let raw = instance.to_raw(node);
+        let origin = cgmath::Matrix4::from(raw.model) * cgmath::Vector4::new(0.0, 0.0, 0.0, 1.0);
+        assert_eq!(origin, cgmath::Vector4::new(1.0, 6.0, 0.0, 1.0));
+
+        let mut set = (0..4).map(|i| at(i as f32)).collect::<InstanceSet>();
+        set.set_nodes(vec![cgmath::Matrix4::identity(), node]);
+        assert_eq!(set.node_range(0), 0..4);
+        assert_eq!(set.node_range(1), 4..8);
+    }
+
+    fn assert_within(instances: &[Instance], min: cgmath::Vector3<f32>, max: cgmath::Vector3<f32>) {
+        const EPS: f32 = 1e-4;
+        for i in instances {
+            let p = i.position;
+            assert!(
+                p.x >= min.x - EPS && p.y >= min.y - EPS && p.z >= min.z - EPS
+                    && p.x <= max.x + EPS && p.y <= max.y + EPS && p.z <= max.z + EPS,
+                "{:?} outside {:?}..{:?}",
+                p,
+                min,
+                max
+            );
+        }
+    }
+
+    #[test]
+    fn grid_layout_counts_and_bounds() {
+        let grid = grid_layout(&[4, 3, 2], &[2.0, 1.0, 10.0]);
+        assert_eq!(grid.len(), 24);
+        assert_within(&grid, cgmath::Vector3::new(-3.0, -5.0, -1.0), cgmath::Vector3::new(3.0, 5.0, 1.0));
+        assert!(grid_layout(&[], &[]).is_empty());
+        let row = grid_layout(&[5], &[1.0]);
+        assert_eq!(row.len(), 5);
+        assert_eq!(row[0].position, cgmath::Vector3::new(-2.0, 0.0, 0.0));
+        assert_eq!(row[4].position, cgmath::Vector3::new(2.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn ring_and_spiral_layout_bounds() {
+        let ring = ring_layout(12, 3.0);
+        assert_eq!(ring.len(), 12);
+        for i in &ring {
+            assert!((i.position.magnitude() - 3.0).abs() < 1e-4);
+            assert_eq!(i.position.y, 0.0);
+        }
+
+        let spiral = spiral_layout(50, 3.0, 4.0, 2.0);
+        assert_eq!(spiral.len(), 50);
+        assert_within(&spiral, cgmath::Vector3::new(-4.0, 0.0, -4.0), cgmath::Vector3::new(4.0, 2.0, 4.0));
+        assert_eq!(spiral[0].position, cgmath::Vector3::zero());
+        assert!((spiral[49].position.y - 2.0).abs() < 1e-4);
+        assert!((cgmath::Vector3::new(spiral[49].position.x, 0.0, spiral[49].position.z).magnitude() - 4.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn scatter_layout_respects_spacing_and_seed() {
+        let (min, max) = (cgmath::Vector3::new(-10.0, 0.0, -10.0), cgmath::Vector3::new(10.0, 0.0, 10.0));
+        let scatter = scatter_layout(100, min, max, 1.5, 7);
+        assert_eq!(scatter.len(), 100);
+        assert_within(&scatter, min, max);
+        for (i, a) in scatter.iter().enumerate() {
+            for b in &scatter[i + 1..] {
+                assert!((a.position - b.position).magnitude() >= 1.5);
+            }
+        }
+
+        let again = scatter_layout(100, min, max, 1.5, 7);
+        assert!(scatter.iter().zip(&again).all(|(a, b)| a.position == b.position));
+        let other = scatter_layout(100, min, max, 1.5, 8);
+        assert!(scatter.iter().zip(&other).any(|(a, b)| a.position != b.position));
+    }
+
+    #[test]
+    fn scatter_layout_stops_when_full() {
+        // a 1x1 square can't hold more than a handful of points 1.0 apart
+        let scatter = scatter_layout(100, cgmath::Vector3::zero(), cgmath::Vector3::new(1.0, 0.0, 1.0), 1.0, 1);
+        assert!(!scatter.is_empty() && scatter.len() <= 4);
+    }
+
+    #[test]
+    fn scatter_layout_with_inverted_bounds_is_empty() {
+        let scatter = scatter_layout(10, cgmath::Vector3::new(1.0, 0.0, 0.0), cgmath::Vector3::zero(), 0.5, 1);
+        assert!(scatter.is_empty());
+        let nan = cgmath::Vector3::new(f32::NAN, 0.0, 0.0);
+        assert!(scatter_layout(10, nan, cgmath::Vector3::zero(), 0.5, 1).is_empty());
+    }
+
+    #[test]
+    fn mesh_vertex_layout_skips_duplicate_positions() {
+        let vertex = |position: [f32; 3], normal: [f32; 3]| crate::model::ModelVertex {
+            position,
+            tex_coords: [0.0; 2],
+            normal,
+            tangent: [0.0; 3],
+            bitangent: [0.0; 3],
+        };
+        let vertices = [
+            vertex([1.0, 0.0, 0.0], [1.0, 0.0, 0.0]),
+            vertex([1.0, 0.0, 0.0], [1.0, 0.0, 0.0]),
+            vertex([0.0, 2.0, 0.0], [0.0, 1.0, 0.0]),
+        ];
+        let layout = mesh_vertex_layout(&vertices);
+        assert_eq!(layout.len(), 2);
+        assert_eq!(layout[1].position, cgmath::Vector3::new(0.0, 2.0, 0.0));
+        // +y follows the normal
+        let up = layout[0].rotation.rotate_vector(cgmath::Vector3::unit_y());
+        assert!((up - cgmath::Vector3::unit_x()).magnitude() < 1e-5);
+    }
+
+    #[test]
+    fn capacity_grows_geometrically() {
+        assert_eq!(grow_capacity(0, 1), MIN_INSTANCE_CAPACITY);
+        assert_eq!(grow_capacity(16, 17), 32);
+        assert_eq!(grow_capacity(16, 100), 128);
+        assert_eq!(grow_capacity(64, 10), 64);
+    }
+}
@@ -6,50 +6,52 @@ struct Uniforms {
 
 @binding(0) @group(0) var<uniform> uniforms: Uniforms;
 
-
 struct Output {
     @builtin(position) position: vec4<f32>,
-    // @location(0) v_position: vec4<f32>,
-    // @location(1) tex_coord: vec2<f32>,
-    // @location(2) v_normal: vec4<f32>,
-    // @location(0) color: vec4<f32>,
+    @location(0) @interpolate(flat) color: vec3<f32>,
+};
+
+// kind values, see lights.rs
+const LIGHT_DIRECTIONAL: u32 = 0u;
+const MAX_LIGHTS: u32 = 16u;
+
+struct Light {
+    position: vec3<f32>,
+    kind: u32,
+    direction: vec3<f32>,
+    range: f32,
+    color: vec3<f32>,
+    intensity: f32,
+    cos_cone: vec2<f32>,
+    shadow_layer: i32,
+    shadow_layers: u32,
 };
 
-struct FragUniforms {
-    light_pos: vec4<f32>,
-    eye_pos: vec4<f32>,
+struct Lights {
+    ambient: vec3<f32>,
+    count: u32,
+    lights: array<Light, MAX_LIGHTS>,
 };
 
-@binding(1) @group(0) var<uniform> frag_uniform: FragUniforms;
+@binding(2) @group(0) var<uniform> lights: Lights;
 
+// one instance per light, a small cube at its position
 @vertex
-fn vs_main(@location(0) pos: vec4<f32>) -> Output {
+fn vs_main(@location(0) pos: vec4<f32>, @builtin(instance_index) index: u32) -> Output {
     var output: Output;
-    let m_pos: vec4<f32> = pos * 0.25 + frag_uniform.light_pos;
-    // output.v_position = m_pos;
-    
-    
-    // output.v_normal = uniforms.norm_mat * norm;
-    // output.tex_coord = tex_coord;
-    output.position = uniforms.view_proj_mat  * m_pos;
-    // ourput.color = 
+    let light = lights.lights[index];
+    output.color = light.color;
+    if (light.kind == LIGHT_DIRECTIONAL) {
+        // nowhere to put it, push it out of the clip volume
+        output.position = vec4<f32>(0.0, 0.0, 2.0, 1.0);
+        return output;
+    }
+    let m_pos = vec4<f32>(pos.xyz * 0.25 + light.position, 1.0);
+    output.position = uniforms.view_proj_mat * m_pos;
     return output;
 }
 
-
-struct LightUniforms {
-    // color: vec4<f32>,
-    specular_color: vec4<f32>,
-    ambient: f32,
-    diffuse: f32,
-    specular_intensity: f32,
-    specular_shininess: f32,
-};
-
-@binding(2) @group(0) var<uniform> light_uniform: LightUniforms;
-
 @fragment
-fn fs_main() -> @location(0) vec4<f32> {
-    
-    return light_uniform.specular_color;
-}
\ No newline at end of file
+fn fs_main(in: Output) -> @location(0) vec4<f32> {
+    return vec4<f32>(in.color, 1.0);
+}
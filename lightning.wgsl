@@ -11,77 +11,389 @@ struct InstanceInput {
     @location(6) model_matrix_1: vec4<f32>,
     @location(7) model_matrix_2: vec4<f32>,
     @location(8) model_matrix_3: vec4<f32>,
+    @location(9) normal_matrix_0: vec3<f32>,
+    @location(10) normal_matrix_1: vec3<f32>,
+    @location(11) normal_matrix_2: vec3<f32>,
+    @location(12) color: vec4<f32>,
 };
 
 struct Output {
     @builtin(position) position: vec4<f32>,
     @location(0) v_position: vec4<f32>,
     @location(1) tex_coord: vec2<f32>,
-    // @location(2) v_normal: vec4<f32>,
+    @location(2) v_normal: vec3<f32>,
+    @location(3) v_tangent: vec3<f32>,
+    @location(4) v_bitangent: vec3<f32>,
+    @location(5) color: vec4<f32>,
 };
 
 @vertex
-fn vs_main(@location(0) pos: vec4<f32>, @location(1) tex_coord: vec2<f32>, @location(2) norm: vec4<f32>, instance: InstanceInput) -> Output {
+fn vs_main(@location(0) pos: vec4<f32>, @location(1) tex_coord: vec2<f32>, @location(2) norm: vec3<f32>,
+    @location(3) tangent: vec3<f32>, @location(4) bitangent: vec3<f32>, instance: InstanceInput) -> Output {
     var output: Output;
-    let model_mat = mat4x4<f32>(
+    // the global model matrix places the whole set of instances
+    let model_mat = uniforms.model_mat * mat4x4<f32>(
         instance.model_matrix_0,
         instance.model_matrix_1,
         instance.model_matrix_2,
         instance.model_matrix_3,
     );
-    let m_pos: vec4<f32> = uniforms.model_mat * pos;
+    let norm_mat = mat3x3<f32>(uniforms.norm_mat[0].xyz, uniforms.norm_mat[1].xyz, uniforms.norm_mat[2].xyz) * mat3x3<f32>(
+        instance.normal_matrix_0,
+        instance.normal_matrix_1,
+        instance.normal_matrix_2,
+    );
+    let m_pos: vec4<f32> = model_mat * pos;
     output.v_position = m_pos;
     
-    
-    // output.v_normal = uniforms.norm_mat * norm;
+    // tangents follow the surface, so they take the model matrix; normals
+    // need the inverse transpose to stay perpendicular under non-uniform scale
+    let model_3x3 = mat3x3<f32>(model_mat[0].xyz, model_mat[1].xyz, model_mat[2].xyz);
+    output.v_normal = norm_mat * norm;
+    output.v_tangent = model_3x3 * tangent;
+    output.v_bitangent = model_3x3 * bitangent;
     output.tex_coord = tex_coord;
-    output.position = uniforms.view_proj_mat * model_mat * pos;
+    output.color = instance.color;
+    output.position = uniforms.view_proj_mat * m_pos;
     return output;
 }
 
 struct FragUniforms {
-    light_pos: vec4<f32>,
     eye_pos: vec4<f32>,
 };
 
 @binding(1) @group(0) var<uniform> frag_uniform: FragUniforms;
 
-struct LightUniforms {
-    // color: vec4<f32>,
-    specular_color: vec4<f32>,
-    ambient: f32,
-    diffuse: f32,
-    specular_intensity: f32,
-    specular_shininess: f32,
+// kind values, see lights.rs
+const LIGHT_DIRECTIONAL: u32 = 0u;
+const LIGHT_POINT: u32 = 1u;
+const LIGHT_SPOT: u32 = 2u;
+const MAX_LIGHTS: u32 = 16u;
+
+struct Light {
+    position: vec3<f32>,
+    kind: u32,
+    direction: vec3<f32>,
+    range: f32,
+    color: vec3<f32>,
+    intensity: f32,
+    // cosines of the inner and outer spot angles
+    cos_cone: vec2<f32>,
+    // first shadow map layer, -1 without shadows, and one layer per cascade
+    shadow_layer: i32,
+    shadow_layers: u32,
 };
 
-@binding(2) @group(0) var<uniform> light_uniform: LightUniforms;
+struct Lights {
+    // multiplies the image-based lighting
+    ambient: vec3<f32>,
+    count: u32,
+    lights: array<Light, MAX_LIGHTS>,
+};
+
+@binding(2) @group(0) var<uniform> lights: Lights;
 
 @binding(0) @group(1) var t_diffuse: texture_2d<f32>;
 @binding(1) @group(1) var s_diffuse: sampler;
 @binding(2) @group(1) var t_normal: texture_2d<f32>;
 @binding(3) @group(1) var s_normal: sampler;
 
+struct MaterialUniforms {
+    ambient_color: vec4<f32>,
+    diffuse_color: vec4<f32>,
+    specular_color: vec4<f32>,
+    emissive_color: vec4<f32>,
+    shininess: f32,
+    optical_density: f32,
+    dissolve: f32,
+    // metallic-roughness shading only
+    metallic: f32,
+    roughness: f32,
+    occlusion_strength: f32,
+};
+
+@binding(4) @group(1) var<uniform> material: MaterialUniforms;
+// metallic in B, roughness in G, as in glTF
+@binding(5) @group(1) var t_metallic_roughness: texture_2d<f32>;
+@binding(6) @group(1) var s_metallic_roughness: sampler;
+@binding(7) @group(1) var t_occlusion: texture_2d<f32>;
+@binding(8) @group(1) var s_occlusion: sampler;
+@binding(9) @group(1) var t_emissive: texture_2d<f32>;
+@binding(10) @group(1) var s_emissive: sampler;
+
+const MAX_SHADOW_MAPS: u32 = 8u;
+
+struct Shadows {
+    light_view_proj: array<mat4x4<f32>, MAX_SHADOW_MAPS>,
+    // normal bias in world units, PCF radius in texels, texel size in uv,
+    // point light bias in world units
+    params: vec4<f32>,
+    // texel size of the point light faces
+    point_params: vec4<f32>,
+};
+
+@binding(0) @group(2) var<uniform> shadows: Shadows;
+@binding(1) @group(2) var t_shadow: texture_depth_2d_array;
+@binding(2) @group(2) var s_shadow: sampler_comparison;
+// six faces per point light, see CUBE_FACES in shadows.rs
+@binding(3) @group(2) var t_point_shadow: texture_depth_2d_array;
+
+// image-based lighting, see environment.rs
+const PREFILTERED_MIPS: f32 = 5.0;
+
+@binding(0) @group(3) var t_irradiance: texture_cube<f32>;
+// roughness 0 to 1 across the mips
+@binding(1) @group(3) var t_prefiltered: texture_cube<f32>;
+// scale and bias to F0 by n.v and roughness
+@binding(2) @group(3) var t_brdf_lut: texture_2d<f32>;
+@binding(3) @group(3) var s_environment: sampler;
+
+// Share of the (2r + 1)^2 taps around `uv` that are closer to the light
+// than `depth`; every tap is itself a bilinear 2x2 comparison.
+fn pcf(maps: texture_depth_2d_array, layer: i32, uv: vec2<f32>, depth: f32, texel: f32) -> f32 {
+    let radius = i32(shadows.params.y);
+    var lit = 0.0;
+    for (var y = -radius; y <= radius; y = y + 1) {
+        for (var x = -radius; x <= radius; x = x + 1) {
+            let offset = vec2<f32>(f32(x), f32(y)) * texel;
+            lit = lit + textureSampleCompareLevel(maps, s_shadow, uv + offset, layer, depth);
+        }
+    }
+    let taps = 2 * radius + 1;
+    return lit / f32(taps * taps);
+}
+
+struct CubeTexel {
+    face: i32,
+    uv: vec2<f32>,
+};
+
+// The face the direction `v` points through and where it crosses it.
+fn cube_texel(v: vec3<f32>) -> CubeTexel {
+    let a = abs(v);
+    var face: i32;
+    var forward: vec3<f32>;
+    var up = vec3<f32>(0.0, 1.0, 0.0);
+    if (a.x >= a.y && a.x >= a.z) {
+        face = select(1, 0, v.x > 0.0);
+        forward = vec3<f32>(sign(v.x), 0.0, 0.0);
+    } else if (a.y >= a.z) {
+        face = select(3, 2, v.y > 0.0);
+        forward = vec3<f32>(0.0, sign(v.y), 0.0);
+        up = vec3<f32>(0.0, 0.0, 1.0);
+    } else {
+        face = select(5, 4, v.z > 0.0);
+        forward = vec3<f32>(0.0, 0.0, sign(v.z));
+    }
+    // the same basis look_to_rh builds for the face's 90 degree view
+    let right = normalize(cross(forward, up));
+    let face_up = cross(right, forward);
+    let ndc = vec2<f32>(dot(right, v), dot(face_up, v)) / dot(forward, v);
+    return CubeTexel(face, ndc * vec2<f32>(0.5, -0.5) + 0.5);
+}
+
+// Point light faces store distance / range, so `pos` is compared by its
+// distance to the light. The faces are plain array layers (no cube view on
+// GL, see POINT_TEXTURE_LAYERS), so every PCF tap steps along the plane
+// tangent to `v` and picks its own face; taps past an edge land on the
+// neighbouring face instead of clamping to the seam.
+fn point_shadow_factor(light: Light, pos: vec3<f32>) -> f32 {
+    let v = pos - light.position;
+    let depth = (length(v) - shadows.params.w) / light.range;
+    // one texel of a 90 degree face, at the distance of `v`
+    let step = 2.0 * shadows.point_params.x * max(abs(v.x), max(abs(v.y), abs(v.z)));
+    let n = normalize(v);
+    let side = normalize(cross(n, select(vec3<f32>(0.0, 1.0, 0.0), vec3<f32>(1.0, 0.0, 0.0), abs(n.y) > 0.9)));
+    let up = cross(side, n);
+    let radius = i32(shadows.params.y);
+    var lit = 0.0;
+    for (var y = -radius; y <= radius; y = y + 1) {
+        for (var x = -radius; x <= radius; x = x + 1) {
+            let tap = cube_texel(v + (side * f32(x) + up * f32(y)) * step);
+            lit = lit + textureSampleCompareLevel(t_point_shadow, s_shadow, tap.uv, light.shadow_layer + tap.face, depth);
+        }
+    }
+    let taps = 2 * radius + 1;
+    return lit / f32(taps * taps);
+}
+
+// 1 where the light reaches `pos`, 0 in full shadow. Cascades are tried
+// nearest first and the first one containing the point is used.
+fn shadow_factor(light: Light, pos: vec3<f32>, normal: vec3<f32>) -> f32 {
+    if (light.shadow_layer < 0) {
+        return 1.0;
+    }
+    // pushing the lookup off the surface keeps it from shadowing itself
+    let biased = vec4<f32>(pos + normal * shadows.params.x, 1.0);
+    if (light.kind == LIGHT_POINT) {
+        return point_shadow_factor(light, biased.xyz);
+    }
+    for (var c = 0u; c < light.shadow_layers; c = c + 1u) {
+        let layer = light.shadow_layer + i32(c);
+        let clip = shadows.light_view_proj[layer] * biased;
+        let ndc = clip.xyz / clip.w;
+        let uv = ndc.xy * vec2<f32>(0.5, -0.5) + 0.5;
+        if (clip.w <= 0.0 || any(uv < vec2<f32>(0.0)) || any(uv > vec2<f32>(1.0)) || ndc.z > 1.0) {
+            continue;
+        }
+        return pcf(t_shadow, layer, uv, ndc.z, shadows.params.z);
+    }
+    return 1.0;
+}
+
+// Inverse square falloff, windowed so it reaches exactly 0 at `range`.
+fn attenuation(distance: f32, range: f32) -> f32 {
+    let window = clamp(1.0 - pow(distance / range, 4.0), 0.0, 1.0);
+    return window * window / max(distance * distance, 0.0001);
+}
+
+struct Incoming {
+    // direction towards the light
+    L: vec3<f32>,
+    // intensity after falloff, spot cone and shadows
+    strength: f32,
+};
+
+fn incoming(light: Light, pos: vec3<f32>, geometry_normal: vec3<f32>) -> Incoming {
+    var result: Incoming;
+    result.strength = light.intensity;
+    if (light.kind == LIGHT_DIRECTIONAL) {
+        result.L = -light.direction;
+    } else {
+        let to_light = light.position - pos;
+        result.L = normalize(to_light);
+        result.strength = result.strength * attenuation(length(to_light), light.range);
+        if (light.kind == LIGHT_SPOT) {
+            result.strength = result.strength * smoothstep(light.cos_cone.y, light.cos_cone.x, dot(-result.L, light.direction));
+        }
+    }
+    result.strength = result.strength * shadow_factor(light, pos, geometry_normal);
+    return result;
+}
+
+// normal mapped shading normal
+fn surface_normal(in: Output) -> vec3<f32> {
+    // normal map stores a tangent-space direction in [0, 1]
+    let tangent_normal = textureSample(t_normal, s_normal, in.tex_coord).xyz * 2.0 - 1.0;
+    let TBN = mat3x3<f32>(normalize(in.v_tangent), normalize(in.v_bitangent), normalize(in.v_normal));
+    return normalize(TBN * tangent_normal);
+}
+
+fn ambient_occlusion(tex_coord: vec2<f32>) -> f32 {
+    let occlusion = textureSample(t_occlusion, s_occlusion, tex_coord).r;
+    return mix(1.0, occlusion, material.occlusion_strength);
+}
+
+fn emissive(tex_coord: vec2<f32>) -> vec3<f32> {
+    return material.emissive_color.xyz * textureSample(t_emissive, s_emissive, tex_coord).rgb;
+}
+
 @fragment
-fn fs_main(@location(0) v_pos: vec4<f32>, @location(1) tex_coord: vec2<f32>) -> @location(0) vec4<f32> {
-    
-    let v_normal = textureSample(t_normal, s_normal, tex_coord);
+fn fs_main(in: Output) -> @location(0) vec4<f32> {
+    let v_pos = in.v_position;
+    let tex_coord = in.tex_coord;
+
     // 法线
-    let N: vec3<f32> = normalize(v_normal.xyz);
-    // 光源入射
-    let L: vec3<f32> = normalize(frag_uniform.light_pos.xyz - v_pos.xyz);
+    let N: vec3<f32> = surface_normal(in);
+    let geometry_normal = normalize(in.v_normal);
     // 相机出射
     let V: vec3<f32> = normalize(frag_uniform.eye_pos.xyz - v_pos.xyz);
-    // 对角线
-    let H = normalize(L + V);
 
+    let obj_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, tex_coord) * material.diffuse_color * in.color;
+    let ao = ambient_occlusion(tex_coord);
+    let irradiance = textureSample(t_irradiance, s_environment, N).rgb;
+    var color: vec3<f32> = lights.ambient * irradiance * material.ambient_color.xyz * obj_color.xyz * ao + emissive(tex_coord);
 
-    let diffuse: f32 = light_uniform.diffuse * max(dot(N, L), 0.0);
-    let specular: f32 = light_uniform.specular_intensity * pow(max(dot(N, H), 0.0), light_uniform.specular_shininess);
-    let ambient: f32 = light_uniform.ambient;
-    let color: vec3<f32> = light_uniform.specular_color.xyz * (specular + ambient + diffuse);
-    let obj_color: vec4<f32> = textureSample(t_diffuse, s_diffuse, tex_coord);
-    return vec4<f32>(color * obj_color.xyz, obj_color.a);
-    // return  light_uniform.specular_color * (specular + ambient + diffuse) * textureSample(t_diffuse, s_diffuse, tex_coord).xyz;
+    for (var i = 0u; i < min(lights.count, MAX_LIGHTS); i = i + 1u) {
+        let light = lights.lights[i];
+        // 光源入射
+        let incoming = incoming(light, v_pos.xyz, geometry_normal);
+        let L = incoming.L;
+        // 对角线
+        let H = normalize(L + V);
+        let diffuse: vec3<f32> = max(dot(N, L), 0.0) * obj_color.xyz;
+        let specular: vec3<f32> = pow(max(dot(N, H), 0.0), material.shininess) * material.specular_color.xyz;
+        color = color + light.color * incoming.strength * (diffuse + specular);
+    }
+    return vec4<f32>(color, obj_color.a * material.dissolve);
+}
+
+const PI: f32 = 3.14159265;
+
+// GGX / Trowbridge-Reitz normal distribution
+fn distribution_ggx(n_dot_h: f32, roughness: f32) -> f32 {
+    let a = roughness * roughness;
+    let a2 = a * a;
+    let d = n_dot_h * n_dot_h * (a2 - 1.0) + 1.0;
+    return a2 / max(PI * d * d, 0.0001);
+}
 
-}
\ No newline at end of file
+// Smith geometry term with the Schlick-GGX approximation for direct light
+fn geometry_smith(n_dot_v: f32, n_dot_l: f32, roughness: f32) -> f32 {
+    let r = roughness + 1.0;
+    let k = r * r / 8.0;
+    let g_v = n_dot_v / (n_dot_v * (1.0 - k) + k);
+    let g_l = n_dot_l / (n_dot_l * (1.0 - k) + k);
+    return g_v * g_l;
+}
+
+fn fresnel_schlick(cos_theta: f32, f0: vec3<f32>) -> vec3<f32> {
+    return f0 + (1.0 - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// rough surfaces reflect less at grazing angles
+fn fresnel_schlick_roughness(cos_theta: f32, f0: vec3<f32>, roughness: f32) -> vec3<f32> {
+    return f0 + (max(vec3<f32>(1.0 - roughness), f0) - f0) * pow(clamp(1.0 - cos_theta, 0.0, 1.0), 5.0);
+}
+
+// Diffuse and specular light from the environment, split-sum approximated.
+fn image_based_light(N: vec3<f32>, V: vec3<f32>, albedo: vec3<f32>, f0: vec3<f32>, metallic: f32, roughness: f32) -> vec3<f32> {
+    let n_dot_v = max(dot(N, V), 0.0);
+    let F = fresnel_schlick_roughness(n_dot_v, f0, roughness);
+    let kd = (1.0 - F) * (1.0 - metallic);
+    let diffuse = textureSample(t_irradiance, s_environment, N).rgb * albedo;
+    let R = reflect(-V, N);
+    let prefiltered = textureSampleLevel(t_prefiltered, s_environment, R, roughness * (PREFILTERED_MIPS - 1.0)).rgb;
+    let brdf = textureSample(t_brdf_lut, s_environment, vec2<f32>(n_dot_v, roughness)).rg;
+    return kd * diffuse + prefiltered * (F * brdf.x + brdf.y);
+}
+
+// Cook-Torrance metallic-roughness shading, see ShadingModel in model.rs
+@fragment
+fn fs_pbr(in: Output) -> @location(0) vec4<f32> {
+    let v_pos = in.v_position;
+    let tex_coord = in.tex_coord;
+
+    let N = surface_normal(in);
+    let geometry_normal = normalize(in.v_normal);
+    let V = normalize(frag_uniform.eye_pos.xyz - v_pos.xyz);
+
+    let base_color = textureSample(t_diffuse, s_diffuse, tex_coord) * material.diffuse_color * in.color;
+    let albedo = base_color.rgb;
+    let mr = textureSample(t_metallic_roughness, s_metallic_roughness, tex_coord);
+    let metallic = clamp(material.metallic * mr.b, 0.0, 1.0);
+    // very low roughness turns point lights into invisible specks
+    let roughness = clamp(material.roughness * mr.g, 0.04, 1.0);
+    let f0 = mix(vec3<f32>(0.04), albedo, metallic);
+    let n_dot_v = max(dot(N, V), 0.0001);
+
+    var color = vec3<f32>(0.0);
+    for (var i = 0u; i < min(lights.count, MAX_LIGHTS); i = i + 1u) {
+        let light = lights.lights[i];
+        let incoming = incoming(light, v_pos.xyz, geometry_normal);
+        let L = incoming.L;
+        let H = normalize(L + V);
+        let n_dot_l = max(dot(N, L), 0.0);
+
+        let F = fresnel_schlick(max(dot(H, V), 0.0), f0);
+        let D = distribution_ggx(max(dot(N, H), 0.0), roughness);
+        let G = geometry_smith(n_dot_v, n_dot_l, roughness);
+        let specular = D * G * F / (4.0 * n_dot_v * max(n_dot_l, 0.0001));
+        // metals have no diffuse lobe
+        let kd = (1.0 - F) * (1.0 - metallic);
+        color = color + (kd * albedo / PI + specular) * light.color * incoming.strength * n_dot_l;
+    }
+    let ambient = image_based_light(N, V, albedo, f0, metallic, roughness) * ambient_occlusion(tex_coord);
+    color = color + lights.ambient * ambient + emissive(tex_coord);
+    return vec4<f32>(color, base_color.a * material.dissolve);
+}
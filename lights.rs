@@ -0,0 +1,186 @@
+use bytemuck::{Pod, Zeroable};
+use cgmath::*;
+
+// size of the light array in lightning.wgsl and light.wgsl
+pub const MAX_LIGHTS: usize = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum LightKind {
+    // shines in every direction, fading out at `range`
+    Point { position: Point3<f32>, range: f32 },
+    // parallel rays along `direction`, like the sun
+    Directional { direction: Vector3<f32> },
+    // a point light limited to a cone around `direction`: full strength
+    // inside `inner_angle`, fading to nothing at `outer_angle`
+    Spot {
+        position: Point3<f32>,
+        direction: Vector3<f32>,
+        range: f32,
+        inner_angle: Rad<f32>,
+        outer_angle: Rad<f32>,
+    },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Light {
+    pub kind: LightKind,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    // gets a shadow map while there are layers left, see shadows.rs
+    pub cast_shadows: bool,
+}
+
+impl Light {
+    pub fn point(position: Point3<f32>, range: f32, color: [f32; 3], intensity: f32) -> Self {
+        Self { kind: LightKind::Point { position, range }, color, intensity, cast_shadows: true }
+    }
+
+    pub fn directional(direction: Vector3<f32>, color: [f32; 3], intensity: f32) -> Self {
+        Self { kind: LightKind::Directional { direction }, color, intensity, cast_shadows: true }
+    }
+
+    pub fn spot(
+        position: Point3<f32>,
+        direction: Vector3<f32>,
+        range: f32,
+        inner_angle: Rad<f32>,
+        outer_angle: Rad<f32>,
+        color: [f32; 3],
+        intensity: f32,
+    ) -> Self {
+        Self {
+            kind: LightKind::Spot { position, direction, range, inner_angle, outer_angle },
+            color,
+            intensity,
+            cast_shadows: true,
+        }
+    }
+
+    // None for directional lights, which are infinitely far away
+    #[cfg(test)]
+    pub fn position(&self) -> Option<Point3<f32>> {
+        match self.kind {
+            LightKind::Point { position, .. } | LightKind::Spot { position, .. } => Some(position),
+            LightKind::Directional { .. } => None,
+        }
+    }
+
+    pub fn to_raw(self) -> LightRaw {
+        let (kind, position, direction, range, cone) = match self.kind {
+            LightKind::Point { position, range } => (LIGHT_POINT, position, Vector3::zero(), range, [0.0, 0.0]),
+            LightKind::Directional { direction } => (LIGHT_DIRECTIONAL, Point3::origin(), direction, 0.0, [0.0, 0.0]),
+            LightKind::Spot { position, direction, range, inner_angle, outer_angle } => {
+                (LIGHT_SPOT, position, direction, range, [inner_angle.cos(), outer_angle.cos()])
+            }
+        };
+        let direction = if direction.magnitude2() > 0.0 { direction.normalize() } else { direction };
+        LightRaw {
+            position: position.into(),
+            kind,
+            direction: direction.into(),
+            range,
+            color: self.color,
+            intensity: self.intensity,
+            cos_cone: cone,
+            shadow_layer: NO_SHADOW,
+            shadow_layers: 0,
+        }
+    }
+}
+
+// LightRaw::kind values, keep in sync with the shaders
+pub const LIGHT_DIRECTIONAL: u32 = 0;
+pub const LIGHT_POINT: u32 = 1;
+pub const LIGHT_SPOT: u32 = 2;
+// LightRaw::shadow_layer of lights without a shadow map
+pub const NO_SHADOW: i32 = -1;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct LightRaw {
+    pub position: [f32; 3],
+    pub kind: u32,
+    // normalized, unused by point lights
+    pub direction: [f32; 3],
+    // 0 for directional lights
+    pub range: f32,
+    pub color: [f32; 3],
+    pub intensity: f32,
+    // cosines of the inner and outer spot angles
+    pub cos_cone: [f32; 2],
+    // first layer of the light's shadow maps, and how many it has (one per
+    // cascade); filled in by ShadowMaps::update
+    pub shadow_layer: i32,
+    pub shadow_layers: u32,
+}
+
+// The light list as one uniform buffer. Uniform rather than storage so it
+// also works on downlevel/WebGL2 adapters, hence the fixed size.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Pod, Zeroable)]
+pub struct LightsUniform {
+    // multiplies the light from the environment maps
+    pub ambient: [f32; 3],
+    pub count: u32,
+    pub lights: [LightRaw; MAX_LIGHTS],
+}
+
+impl LightsUniform {
+    // lights past MAX_LIGHTS are dropped
+    pub fn new(ambient: [f32; 3], lights: &[Light]) -> Self {
+        if lights.len() > MAX_LIGHTS {
+            log::warn!("{} lights, only the first {} are used", lights.len(), MAX_LIGHTS);
+        }
+        let mut uniform = Self::zeroed();
+        uniform.ambient = ambient;
+        for (raw, light) in uniform.lights.iter_mut().zip(lights) {
+            *raw = light.to_raw();
+        }
+        uniform.count = lights.len().min(MAX_LIGHTS) as u32;
+        uniform
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raw_layout_matches_wgsl() {
+        // vec3 + u32, vec3 + f32, vec3 + f32, vec2 + i32 + u32
+        assert_eq!(std::mem::size_of::<LightRaw>(), 64);
+        assert_eq!(std::mem::size_of::<LightsUniform>(), 16 + 64 * MAX_LIGHTS);
+    }
+
+    #[test]
+    fn raw_lights_carry_kind_and_cone() {
+        let spot = Light::spot(
+            (1.0, 2.0, 3.0).into(),
+            (0.0, -2.0, 0.0).into(),
+            10.0,
+            Rad(0.0),
+            Deg(60.0).into(),
+            [1.0, 0.5, 0.0],
+            2.0,
+        )
+        .to_raw();
+        assert_eq!(spot.kind, LIGHT_SPOT);
+        assert_eq!(spot.position, [1.0, 2.0, 3.0]);
+        assert_eq!(spot.direction, [0.0, -1.0, 0.0]);
+        assert_eq!(spot.range, 10.0);
+        assert!((spot.cos_cone[0] - 1.0).abs() < 1e-6 && (spot.cos_cone[1] - 0.5).abs() < 1e-6);
+
+        let sun = Light::directional((1.0, 0.0, 0.0).into(), [1.0; 3], 1.0);
+        assert_eq!(sun.to_raw().kind, LIGHT_DIRECTIONAL);
+        assert_eq!(sun.position(), None);
+        assert_eq!(Light::point((0.0, 1.0, 0.0).into(), 5.0, [1.0; 3], 1.0).to_raw().kind, LIGHT_POINT);
+    }
+
+    #[test]
+    fn uniform_drops_lights_past_the_limit() {
+        let lights = vec![Light::point(Point3::origin(), 1.0, [1.0; 3], 1.0); MAX_LIGHTS + 3];
+        let uniform = LightsUniform::new([0.1; 3], &lights);
+        assert_eq!(uniform.count, MAX_LIGHTS as u32);
+        assert_eq!(LightsUniform::new([0.1; 3], &lights[..2]).count, 2);
+    }
+}
@@ -4,19 +4,246 @@ use winit::{
     window::{WindowBuilder},
 };
 
+mod camera;
 mod common;
 mod transforms;
 mod texture;
 mod instancing;
+mod lights;
 mod model;
+mod pipeline;
+mod render_targets;
 mod resources;
+mod shadows;
+mod environment;
+#[cfg(test)]
+mod golden;
+
+// Instances for stress scenes and demos, from --layout=grid:10x10x2,
+// ring:24, spiral:200, scatter:300 or mesh:<file>.
+async fn load_layout(value: &str) -> anyhow::Result<Vec<instancing::Instance>> {
+    const SPACING: f32 = 3.0;
+    let (kind, arg) = value.split_once(':').unwrap_or((value, ""));
+    let invalid = || anyhow::anyhow!("invalid value {:?} for --layout={}", arg, kind);
+    let count = || arg.parse::<u32>().map_err(|_| invalid());
+    Ok(match kind {
+        "grid" => {
+            let counts = arg.split('x').map(str::parse::<u32>).collect::<Result<Vec<_>, _>>().map_err(|_| invalid())?;
+            if counts.len() > 3 {
+                return Err(invalid());
+            }
+            instancing::grid_layout(&counts, &vec![SPACING; counts.len()])
+        }
+        "ring" => {
+            let count = count()?;
+            instancing::ring_layout(count, (count as f32 * SPACING / std::f32::consts::TAU).max(SPACING))
+        }
+        "spiral" => instancing::spiral_layout(count()?, 4.0, 20.0, 10.0),
+        "scatter" => {
+            let extent = cgmath::Vector3::new(20.0, 0.0, 20.0);
+            instancing::scatter_layout(count()? as usize, -extent, extent, SPACING, 0)
+        }
+        "mesh" => instancing::mesh_vertex_layout(&resources::load_vertices(arg).await?),
+        _ => anyhow::bail!("unknown layout {:?}, expected grid, ring, spiral, scatter or mesh", kind),
+    })
+}
+
+// Scene lights from --light=point:x,y,z, sun:dx,dy,dz or
+// spot:x,y,z:dx,dy,dz, all white.
+fn parse_light(value: &str) -> anyhow::Result<lights::Light> {
+    let invalid = || anyhow::anyhow!("invalid value {:?} for --light", value);
+    let vector = |arg: Option<&str>| -> anyhow::Result<cgmath::Vector3<f32>> {
+        let v = arg.unwrap_or("").split(',').map(|v| v.trim().parse::<f32>()).collect::<Result<Vec<_>, _>>();
+        match v.as_deref() {
+            Ok(&[x, y, z]) => Ok(cgmath::Vector3::new(x, y, z)),
+            _ => Err(invalid()),
+        }
+    };
+    let point = |arg| vector(arg).map(|v| cgmath::Point3::new(v.x, v.y, v.z));
+    let mut parts = value.split(':');
+    let kind = parts.next().unwrap_or("");
+    let light = match kind {
+        "point" => lights::Light::point(point(parts.next())?, 20.0, [1.0; 3], 10.0),
+        "sun" => lights::Light::directional(vector(parts.next())?, [1.0; 3], 1.0),
+        "spot" => lights::Light::spot(
+            point(parts.next())?,
+            vector(parts.next())?,
+            20.0,
+            cgmath::Deg(15.0).into(),
+            cgmath::Deg(25.0).into(),
+            [1.0; 3],
+            20.0,
+        ),
+        _ => anyhow::bail!("unknown light {:?}, expected point, sun or spot", kind),
+    };
+    if parts.next().is_some() {
+        return Err(invalid());
+    }
+    Ok(light)
+}
 
 fn main() {
     env_logger::init();
+    let headless = std::env::args().any(|arg| arg == "--headless");
+    let depth_mode = if std::env::args().any(|arg| arg == "--reverse-z") {
+        transforms::DepthMode::ReverseZ
+    } else {
+        transforms::DepthMode::Standard
+    };
+    let sample_count = match std::env::args().find_map(|arg| arg.strip_prefix("--msaa=").map(str::to_string)) {
+        None => 1,
+        Some(value) => match value.parse::<u32>() {
+            Ok(count @ (1 | 2 | 4 | 8)) => count,
+            _ => {
+                eprintln!("invalid value {:?} for --msaa, expected 1, 2, 4 or 8", value);
+                std::process::exit(2);
+            }
+        },
+    };
+    // constant and slope-scaled depth bias of the shadow maps, e.g. --shadow-bias=2,2.0
+    let shadow_bias = match std::env::args().find_map(|arg| arg.strip_prefix("--shadow-bias=").map(str::to_string)) {
+        None => None,
+        Some(value) => match value.split_once(',').map(|(c, s)| (c.trim().parse::<i32>(), s.trim().parse::<f32>())) {
+            Some((Ok(constant), Ok(slope))) => Some((constant, slope)),
+            _ => {
+                eprintln!("invalid value {:?} for --shadow-bias, expected <constant>,<slope>", value);
+                std::process::exit(2);
+            }
+        },
+    };
+    // replace the default lights, e.g. --light=spot:0,5,0:0,-1,0 --light=sun:1,-1,0
+    let scene_lights = match std::env::args()
+        .filter_map(|arg| arg.strip_prefix("--light=").map(parse_light))
+        .collect::<anyhow::Result<Vec<_>>>()
+    {
+        Ok(scene_lights) => scene_lights,
+        Err(e) => {
+            eprintln!("{:#}", e);
+            std::process::exit(2);
+        }
+    };
+    let ambient = match std::env::args().find_map(|arg| arg.strip_prefix("--ambient=").map(str::to_string)) {
+        None => None,
+        Some(value) => match value.split(',').map(|v| v.trim().parse::<f32>()).collect::<Result<Vec<_>, _>>().as_deref() {
+            Ok(&[r, g, b]) => Some([r, g, b]),
+            _ => {
+                eprintln!("invalid value {:?} for --ambient, expected <r>,<g>,<b>", value);
+                std::process::exit(2);
+            }
+        },
+    };
+    // equirectangular .hdr image that lights the scene and replaces the backdrop
+    let environment = std::env::args().find_map(|arg| arg.strip_prefix("--environment=").map(str::to_string));
+    let environment = match environment.map(|file| pollster::block_on(resources::load_hdr(&file))).transpose() {
+        Ok(environment) => environment,
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    // OBJ or glTF/GLB file drawn instead of the cube
+    let model = std::env::args().find_map(|arg| arg.strip_prefix("--model=").map(str::to_string));
+    let layout = std::env::args().find_map(|arg| arg.strip_prefix("--layout=").map(str::to_string));
+    let layout = match layout.map(|value| pollster::block_on(load_layout(&value))).transpose() {
+        Ok(layout) => layout,
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    let init_config = if headless {
+        transforms::InitConfig::headless()
+    } else {
+        transforms::InitConfig::default()
+    };
+    let init_config = match init_config
+        .with_env()
+        .and_then(|config| config.with_args(std::env::args().skip(1)))
+    {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    if headless {
+        let mut state = match pollster::block_on(common::State::new_headless(800, 600, &init_config)) {
+            Ok(state) => state,
+            Err(e) => {
+                eprintln!("error: {:#}", e);
+                std::process::exit(1);
+            }
+        };
+        state.set_depth_mode(depth_mode);
+        if let Err(e) = state.set_sample_count(sample_count) {
+            eprintln!("error: {:#}", e);
+            std::process::exit(1);
+        }
+        if let Some(environment) = &environment {
+            state.set_environment(environment);
+        }
+        if let Some((depth_bias, slope_bias)) = shadow_bias {
+            state.set_shadow_settings(shadows::ShadowSettings { depth_bias, slope_bias, ..state.shadow_settings() });
+        }
+        if let Some(model) = &model {
+            if let Err(e) = pollster::block_on(state.set_model(model)) {
+                eprintln!("error: {:#}", e);
+                std::process::exit(1);
+            }
+        }
+        if let Some(layout) = layout {
+            state.set_instances(layout);
+        }
+        if !scene_lights.is_empty() {
+            state.set_lights(scene_lights);
+        }
+        if let Some(ambient) = ambient {
+            state.set_ambient(ambient);
+        }
+        state.update(std::time::Duration::ZERO);
+        state.render().unwrap();
+        if let Err(e) = state.render_to_file("frame.png") {
+            eprintln!("error: {:#}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new().build(&event_loop).unwrap();
     window.set_title(&*format!("{}", "cube rotation"));
-    let mut state = pollster::block_on(common::State::new(&window));
+    let mut state = match pollster::block_on(common::State::new(&window, &init_config)) {
+        Ok(state) => state,
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            std::process::exit(1);
+        }
+    };
+    state.set_depth_mode(depth_mode);
+    if let Err(e) = state.set_sample_count(sample_count) {
+        eprintln!("error: {:#}", e);
+        std::process::exit(1);
+    }
+    if let Some(environment) = &environment {
+        state.set_environment(environment);
+    }
+    if let Some((depth_bias, slope_bias)) = shadow_bias {
+        state.set_shadow_settings(shadows::ShadowSettings { depth_bias, slope_bias, ..state.shadow_settings() });
+    }
+    if let Some(model) = &model {
+        if let Err(e) = pollster::block_on(state.set_model(model)) {
+            eprintln!("error: {:#}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(layout) = layout {
+        state.set_instances(layout);
+    }
+    if !scene_lights.is_empty() {
+        state.set_lights(scene_lights);
+    }
+    if let Some(ambient) = ambient {
+        state.set_ambient(ambient);
+    }
 
     let start_time = std::time::Instant::now();
 
@@ -48,6 +275,9 @@ fn main() {
                     }
                 }
             }
+            Event::DeviceEvent { ref event, .. } => {
+                state.device_input(event);
+            }
             Event::RedrawRequested(_) => {
                 let now = std::time::Instant::now();
                 let dt = now - start_time;
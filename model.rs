@@ -1,6 +1,8 @@
 use std::ops::Range;
 
-use crate::texture;
+use wgpu::util::DeviceExt;
+
+use crate::{instancing, pipeline, texture};
 
 pub trait Vertex {
     fn desc() -> wgpu::VertexBufferLayout<'static>;
@@ -12,6 +14,8 @@ pub struct ModelVertex {
     pub position: [f32; 3],
     pub tex_coords: [f32; 2],
     pub normal: [f32; 3],
+    pub tangent: [f32; 3],
+    pub bitangent: [f32; 3],
 }
 
 impl ModelVertex {
@@ -54,6 +58,17 @@ impl Vertex for ModelVertex {
                     shader_location: 2,
                     format: wgpu::VertexFormat::Float32x3,
                 },
+                // Tangent and bitangent
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 8]>() as wgpu::BufferAddress,
+                    shader_location: 3,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
+                wgpu::VertexAttribute {
+                    offset: mem::size_of::<[f32; 11]>() as wgpu::BufferAddress,
+                    shader_location: 4,
+                    format: wgpu::VertexFormat::Float32x3,
+                },
             ],
         }
     }
@@ -73,11 +88,183 @@ impl ModelRaw {
     }
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct MaterialUniform {
+    // Ka
+    pub ambient_color: [f32; 4],
+    // Kd, multiplied with the diffuse map
+    pub diffuse_color: [f32; 4],
+    // Ks
+    pub specular_color: [f32; 4],
+    // Ke
+    pub emissive_color: [f32; 4],
+    // Ns
+    pub shininess: f32,
+    // Ni
+    pub optical_density: f32,
+    // d, 1.0 is fully opaque
+    pub dissolve: f32,
+    // metallic-roughness only, multiplied with the B and G channels of the
+    // metallic-roughness map (Pm and Pr in MTL)
+    pub metallic: f32,
+    pub roughness: f32,
+    // how much the occlusion map darkens ambient light, 0 ignores it
+    pub occlusion_strength: f32,
+    pub _padding: [f32; 2],
+}
+
+impl Default for MaterialUniform {
+    fn default() -> Self {
+        Self {
+            ambient_color: [1.0, 1.0, 1.0, 1.0],
+            diffuse_color: [1.0, 1.0, 1.0, 1.0],
+            specular_color: [1.0, 1.0, 1.0, 1.0],
+            emissive_color: [0.0, 0.0, 0.0, 1.0],
+            shininess: 32.0,
+            optical_density: 1.0,
+            dissolve: 1.0,
+            metallic: 0.0,
+            roughness: 0.5,
+            occlusion_strength: 1.0,
+            _padding: [0.0; 2],
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Default)]
+pub enum ShadingModel {
+    // MTL style ambient, diffuse and specular colors
+    #[default]
+    BlinnPhong,
+    // Cook-Torrance GGX driven by the metallic and roughness factors, as in glTF
+    MetallicRoughness,
+}
+
+// Everything a material samples. Colors are sRGB, the other maps linear.
+pub struct MaterialTextures {
+    pub diffuse: texture::Texture,
+    pub normal: texture::Texture,
+    // glTF packing: roughness in G, metallic in B
+    pub metallic_roughness: texture::Texture,
+    // ambient occlusion in R
+    pub occlusion: texture::Texture,
+    // multiplied with the emissive color
+    pub emissive: texture::Texture,
+}
+
+impl MaterialTextures {
+    // 1x1 maps that leave the uniform's colors and factors as they are
+    pub fn defaults(device: &wgpu::Device, queue: &wgpu::Queue) -> anyhow::Result<Self> {
+        Ok(Self {
+            diffuse: texture::Texture::default_diffuse(device, queue)?,
+            normal: texture::Texture::default_normal(device, queue)?,
+            metallic_roughness: texture::Texture::default_data(device, queue)?,
+            occlusion: texture::Texture::default_data(device, queue)?,
+            emissive: texture::Texture::default_diffuse(device, queue)?,
+        })
+    }
+}
+
 pub struct Material {
     pub name: String,
-    pub diffuse_texture: texture::Texture,
-    pub normal_texture: texture::Texture,
+    // uploaded by State::edit_materials
+    pub uniform: MaterialUniform,
+    pub uniform_buffer: wgpu::Buffer,
     pub bind_group: wgpu::BindGroup,
+    // pipeline variant: no back-face culling
+    pub double_sided: bool,
+    // blended materials are drawn after opaque ones, without depth writes
+    pub blend: pipeline::BlendMode,
+    // pipeline variant: which fragment shader lights the material
+    pub shading_model: ShadingModel,
+}
+
+impl Material {
+    pub fn new(
+        device: &wgpu::Device,
+        name: &str,
+        textures: MaterialTextures,
+        uniform: MaterialUniform,
+        layout: &wgpu::BindGroupLayout,
+    ) -> Self {
+        // the bind group keeps the views and samplers alive, so `textures`
+        // is dropped once it is created
+        let uniform_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some(&format!("{:?} Material Buffer", name)),
+            contents: bytemuck::cast_slice(&[uniform]),
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+        });
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&textures.diffuse.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&textures.diffuse.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: wgpu::BindingResource::TextureView(&textures.normal.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 3,
+                    resource: wgpu::BindingResource::Sampler(&textures.normal.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 4,
+                    resource: uniform_buffer.as_entire_binding(),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 5,
+                    resource: wgpu::BindingResource::TextureView(&textures.metallic_roughness.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 6,
+                    resource: wgpu::BindingResource::Sampler(&textures.metallic_roughness.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 7,
+                    resource: wgpu::BindingResource::TextureView(&textures.occlusion.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 8,
+                    resource: wgpu::BindingResource::Sampler(&textures.occlusion.sampler),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 9,
+                    resource: wgpu::BindingResource::TextureView(&textures.emissive.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 10,
+                    resource: wgpu::BindingResource::Sampler(&textures.emissive.sampler),
+                },
+            ],
+            label: Some(name),
+        });
+
+        Self {
+            name: name.to_string(),
+            uniform,
+            uniform_buffer,
+            bind_group,
+            double_sided: false,
+            // see-through MTL materials (d < 1) need blending to show it
+            blend: if uniform.dissolve < 1.0 {
+                pipeline::BlendMode::Alpha
+            } else {
+                pipeline::BlendMode::Replace
+            },
+            shading_model: ShadingModel::BlinnPhong,
+        }
+    }
+
+    pub fn write_uniform(&self, queue: &wgpu::Queue) {
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[self.uniform]));
+    }
 }
 
 pub struct Mesh {
@@ -86,11 +273,52 @@ pub struct Mesh {
     pub index_buffer: wgpu::Buffer,
     pub num_elements: u32,
     pub material: usize,
+    // index into Model::nodes of the node placing the mesh
+    pub node: usize,
+}
+
+// Scene graph entry of a model. OBJ files have a single identity root,
+// glTF files keep their node hierarchy.
+pub struct Node {
+    // relative to the parent node
+    pub transform: cgmath::Matrix4<f32>,
+    // always an earlier index, so parents come before their children
+    pub parent: Option<usize>,
+}
+
+impl Node {
+    pub fn root() -> Self {
+        Self {
+            transform: cgmath::SquareMatrix::identity(),
+            parent: None,
+        }
+    }
 }
 
 pub struct Model {
     pub meshes: Vec<Mesh>,
     pub materials: Vec<Material>,
+    pub nodes: Vec<Node>,
+}
+
+impl Model {
+    // Model-space transform of every node, applied to the instances at draw
+    // time.
+    pub fn world_transforms(&self) -> Vec<cgmath::Matrix4<f32>> {
+        world_transforms(&self.nodes)
+    }
+}
+
+pub fn world_transforms(nodes: &[Node]) -> Vec<cgmath::Matrix4<f32>> {
+    let mut world: Vec<cgmath::Matrix4<f32>> = Vec::with_capacity(nodes.len());
+    for node in nodes {
+        let transform = match node.parent {
+            Some(parent) => world[parent] * node.transform,
+            None => node.transform,
+        };
+        world.push(transform);
+    }
+    world
 }
 
 pub trait DrawModel<'a> {
@@ -127,6 +355,9 @@ pub trait DrawModel<'a> {
         instances: Range<u32>,
         camera_bind_group: &'a wgpu::BindGroup,
     );
+    // geometry only, the caller sets up pipeline, bind groups and the
+    // instance buffer of `instances`
+    fn draw_model_depth(&mut self, model: &'a Model, instances: &instancing::InstanceSet);
 }
 
 impl<'a, 'b> DrawModel<'b> for wgpu::RenderPass<'a>
@@ -197,4 +428,12 @@ where
             self.draw_light_instanced(mesh, instances.clone(), camera_bind_group);
         }
     }
-}
\ No newline at end of file
+
+    fn draw_model_depth(&mut self, model: &'b Model, instances: &instancing::InstanceSet) {
+        for mesh in &model.meshes {
+            self.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+            self.set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+            self.draw_indexed(0..mesh.num_elements, 0, instances.node_range(mesh.node));
+        }
+    }
+}
@@ -0,0 +1,543 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::transforms;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Default)]
+pub enum BlendMode {
+    // opaque, the fragment overwrites the target
+    #[default]
+    Replace,
+    // src * a + dst * (1 - a)
+    Alpha,
+    // src * a + dst, for glows and particles
+    Additive,
+    // src + dst * (1 - a), for colors already multiplied by alpha
+    Premultiplied,
+}
+
+impl BlendMode {
+    pub fn state(self) -> wgpu::BlendState {
+        use wgpu::{BlendComponent, BlendFactor, BlendOperation};
+        let component = |src_factor, dst_factor| BlendComponent {
+            src_factor,
+            dst_factor,
+            operation: BlendOperation::Add,
+        };
+        match self {
+            BlendMode::Replace => wgpu::BlendState::REPLACE,
+            BlendMode::Alpha => wgpu::BlendState::ALPHA_BLENDING,
+            BlendMode::Additive => wgpu::BlendState {
+                color: component(BlendFactor::SrcAlpha, BlendFactor::One),
+                alpha: component(BlendFactor::One, BlendFactor::One),
+            },
+            BlendMode::Premultiplied => wgpu::BlendState::PREMULTIPLIED_ALPHA_BLENDING,
+        }
+    }
+
+    // the preset after this one, wrapping around to Replace
+    pub fn next(self) -> Self {
+        match self {
+            BlendMode::Replace => BlendMode::Alpha,
+            BlendMode::Alpha => BlendMode::Additive,
+            BlendMode::Additive => BlendMode::Premultiplied,
+            BlendMode::Premultiplied => BlendMode::Replace,
+        }
+    }
+}
+
+// Describes a render pipeline with the settings the scene pipelines share as
+// defaults: vs_main/fs_main, opaque blending, TriangleList, CCW front faces
+// with back faces culled, filled polygons and no depth buffer. Without a
+// layout wgpu derives one from the shader.
+//
+//     let pipeline = PipelineBuilder::new(shader, config.format)
+//         .layout(&layout)
+//         .vertex_layouts(&[ModelVertex::desc()])
+//         .depth(DepthMode::Standard)
+//         .blend(BlendMode::Alpha)
+//         .depth_write(false)
+//         .build(&device);
+pub struct PipelineBuilder<'a> {
+    label: Option<&'a str>,
+    layout: Option<&'a wgpu::PipelineLayout>,
+    shader: Shader<'a>,
+    vs_entry: &'a str,
+    fs_entry: &'a str,
+    vertex_layouts: &'a [wgpu::VertexBufferLayout<'a>],
+    color_format: wgpu::TextureFormat,
+    blend: BlendMode,
+    topology: wgpu::PrimitiveTopology,
+    strip_index_format: Option<wgpu::IndexFormat>,
+    front_face: wgpu::FrontFace,
+    cull_mode: Option<wgpu::Face>,
+    polygon_mode: wgpu::PolygonMode,
+    depth_mode: Option<transforms::DepthMode>,
+    depth_write: bool,
+    depth_compare: Option<wgpu::CompareFunction>,
+    depth_bias: wgpu::DepthBiasState,
+    sample_count: u32,
+    depth_only: bool,
+    depth_fragment: bool,
+}
+
+impl<'a> PipelineBuilder<'a> {
+    pub fn new(shader: wgpu::ShaderModuleDescriptor<'a>, color_format: wgpu::TextureFormat) -> Self {
+        Self::from_shader(Shader::Descriptor(shader), color_format)
+    }
+
+    // for shaders compiled once and shared between pipelines
+    pub fn with_module(module: &'a wgpu::ShaderModule, color_format: wgpu::TextureFormat) -> Self {
+        Self::from_shader(Shader::Module(module), color_format)
+    }
+
+    fn from_shader(shader: Shader<'a>, color_format: wgpu::TextureFormat) -> Self {
+        Self {
+            label: None,
+            layout: None,
+            shader,
+            vs_entry: "vs_main",
+            fs_entry: "fs_main",
+            vertex_layouts: &[],
+            color_format,
+            blend: BlendMode::Replace,
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: Some(wgpu::Face::Back),
+            polygon_mode: wgpu::PolygonMode::Fill,
+            depth_mode: None,
+            depth_write: true,
+            depth_compare: None,
+            depth_bias: wgpu::DepthBiasState::default(),
+            sample_count: 1,
+            depth_only: false,
+            depth_fragment: false,
+        }
+    }
+
+    pub fn label(mut self, label: &'a str) -> Self {
+        self.label = Some(label);
+        self
+    }
+
+    pub fn layout(mut self, layout: &'a wgpu::PipelineLayout) -> Self {
+        self.layout = Some(layout);
+        self
+    }
+
+    pub fn entry_points(mut self, vs_entry: &'a str, fs_entry: &'a str) -> Self {
+        self.vs_entry = vs_entry;
+        self.fs_entry = fs_entry;
+        self
+    }
+
+    pub fn vertex_layouts(mut self, vertex_layouts: &'a [wgpu::VertexBufferLayout<'a>]) -> Self {
+        self.vertex_layouts = vertex_layouts;
+        self
+    }
+
+    pub fn blend(mut self, blend: BlendMode) -> Self {
+        self.blend = blend;
+        self
+    }
+
+    // Strips drawn with an index buffer also need strip_index_format, so a
+    // primitive restart value can be recognised.
+    pub fn topology(mut self, topology: wgpu::PrimitiveTopology) -> Self {
+        self.topology = topology;
+        self
+    }
+
+    pub fn strip_index_format(mut self, format: wgpu::IndexFormat) -> Self {
+        self.strip_index_format = Some(format);
+        self
+    }
+
+    pub fn front_face(mut self, front_face: wgpu::FrontFace) -> Self {
+        self.front_face = front_face;
+        self
+    }
+
+    pub fn cull_mode(mut self, cull_mode: Option<wgpu::Face>) -> Self {
+        self.cull_mode = cull_mode;
+        self
+    }
+
+    // Line and Point need POLYGON_MODE_LINE / POLYGON_MODE_POINT, build()
+    // falls back to Fill on devices without them.
+    pub fn polygon_mode(mut self, polygon_mode: wgpu::PolygonMode) -> Self {
+        self.polygon_mode = polygon_mode;
+        self
+    }
+
+    // Adds a depth attachment laid out for `depth_mode`, which also picks the
+    // compare function unless depth_compare overrides it.
+    pub fn depth(mut self, depth_mode: transforms::DepthMode) -> Self {
+        self.depth_mode = Some(depth_mode);
+        self
+    }
+
+    pub fn depth_write(mut self, enabled: bool) -> Self {
+        self.depth_write = enabled;
+        self
+    }
+
+    pub fn depth_compare(mut self, compare: wgpu::CompareFunction) -> Self {
+        self.depth_compare = Some(compare);
+        self
+    }
+
+    pub fn depth_bias(mut self, constant: i32, slope_scale: f32, clamp: f32) -> Self {
+        self.depth_bias = wgpu::DepthBiasState {
+            constant,
+            slope_scale,
+            clamp,
+        };
+        self
+    }
+
+    pub fn sample_count(mut self, sample_count: u32) -> Self {
+        self.sample_count = sample_count;
+        self
+    }
+
+    // No fragment stage and no color target, for passes like shadow maps
+    // that only write depth. The color format and blend mode are ignored.
+    pub fn depth_only(mut self) -> Self {
+        self.depth_only = true;
+        self
+    }
+
+    // Like depth_only, but keeps the fragment stage for shaders that write
+    // frag_depth or discard.
+    pub fn depth_only_fragment(mut self) -> Self {
+        self.depth_only = true;
+        self.depth_fragment = true;
+        self
+    }
+
+    pub fn build(self, device: &wgpu::Device) -> wgpu::RenderPipeline {
+        let primitive = self.primitive_state(device.features());
+        let depth_stencil = self.depth_stencil_state();
+        let color_target = [Some(wgpu::ColorTargetState {
+            format: self.color_format,
+            blend: Some(self.blend.state()),
+            write_mask: wgpu::ColorWrites::ALL,
+        })];
+        let targets: &[_] = if self.depth_only { &[] } else { &color_target };
+        let created;
+        let shader = match self.shader {
+            Shader::Descriptor(descriptor) => {
+                created = device.create_shader_module(descriptor);
+                &created
+            }
+            Shader::Module(module) => module,
+        };
+
+        device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+            label: self.label.or(Some("Render Pipeline")),
+            layout: self.layout,
+            vertex: wgpu::VertexState {
+                module: shader,
+                entry_point: self.vs_entry,
+                buffers: self.vertex_layouts,
+            },
+            fragment: (!self.depth_only || self.depth_fragment).then_some(wgpu::FragmentState {
+                module: shader,
+                entry_point: self.fs_entry,
+                targets,
+            }),
+            primitive,
+            depth_stencil,
+            multisample: wgpu::MultisampleState {
+                count: self.sample_count,
+                mask: !0,
+                alpha_to_coverage_enabled: false,
+            },
+            // If the pipeline will be used with a multiview render pass, this
+            // indicates how many array layers the attachments will have.
+            multiview: None,
+        })
+    }
+
+    fn primitive_state(&self, features: wgpu::Features) -> wgpu::PrimitiveState {
+        let required = match self.polygon_mode {
+            wgpu::PolygonMode::Fill => wgpu::Features::empty(),
+            wgpu::PolygonMode::Line => wgpu::Features::POLYGON_MODE_LINE,
+            wgpu::PolygonMode::Point => wgpu::Features::POLYGON_MODE_POINT,
+        };
+        let polygon_mode = if features.contains(required) {
+            self.polygon_mode
+        } else {
+            log::warn!("{:?} polygon mode needs {:?}, drawing filled polygons", self.polygon_mode, required);
+            wgpu::PolygonMode::Fill
+        };
+        let strip = matches!(
+            self.topology,
+            wgpu::PrimitiveTopology::LineStrip | wgpu::PrimitiveTopology::TriangleStrip
+        );
+        wgpu::PrimitiveState {
+            topology: self.topology,
+            // only valid on strip topologies
+            strip_index_format: self.strip_index_format.filter(|_| strip),
+            front_face: self.front_face,
+            cull_mode: self.cull_mode,
+            polygon_mode,
+            // Requires Features::DEPTH_CLIP_CONTROL
+            unclipped_depth: false,
+            // Requires Features::CONSERVATIVE_RASTERIZATION
+            conservative: false,
+        }
+    }
+
+    fn depth_stencil_state(&self) -> Option<wgpu::DepthStencilState> {
+        self.depth_mode.map(|mode| wgpu::DepthStencilState {
+            format: mode.format(),
+            depth_write_enabled: self.depth_write,
+            depth_compare: self.depth_compare.unwrap_or(mode.compare()),
+            stencil: wgpu::StencilState::default(),
+            bias: self.depth_bias,
+        })
+    }
+}
+
+enum Shader<'a> {
+    Descriptor(wgpu::ShaderModuleDescriptor<'a>),
+    Module(&'a wgpu::ShaderModule),
+}
+
+// Everything that tells one cached pipeline from another. Shaders and
+// layouts are referred to by the ids they were added to the cache with.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PipelineKey {
+    pub shader: &'static str,
+    // fragment entry point in `shader`, which is entered at vs_main
+    pub fs_entry: &'static str,
+    pub layout: &'static str,
+    pub vertex_layouts: Vec<wgpu::VertexBufferLayout<'static>>,
+    pub color_format: wgpu::TextureFormat,
+    pub depth: Option<transforms::DepthMode>,
+    pub depth_write: bool,
+    // None compares as `depth` prescribes
+    pub depth_compare: Option<wgpu::CompareFunction>,
+    pub blend: BlendMode,
+    pub cull_mode: Option<wgpu::Face>,
+    pub front_face: wgpu::FrontFace,
+    pub topology: wgpu::PrimitiveTopology,
+    pub strip_index_format: Option<wgpu::IndexFormat>,
+    pub polygon_mode: wgpu::PolygonMode,
+    pub sample_count: u32,
+}
+
+impl PipelineKey {
+    // an opaque, back-face culled triangle list, like PipelineBuilder's defaults
+    pub fn new(
+        shader: &'static str,
+        layout: &'static str,
+        vertex_layouts: Vec<wgpu::VertexBufferLayout<'static>>,
+        color_format: wgpu::TextureFormat,
+    ) -> Self {
+        Self {
+            shader,
+            fs_entry: "fs_main",
+            layout,
+            vertex_layouts,
+            color_format,
+            depth: None,
+            depth_write: true,
+            depth_compare: None,
+            blend: BlendMode::Replace,
+            cull_mode: Some(wgpu::Face::Back),
+            front_face: wgpu::FrontFace::Ccw,
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            polygon_mode: wgpu::PolygonMode::Fill,
+            sample_count: 1,
+        }
+    }
+}
+
+// Builds each distinct pipeline once and hands out shared references, so
+// materials and passes can ask for the variant they need by description.
+// Pipelines are created in get_or_create, which needs `&mut self`; draw
+// code that only has `&self` looks up what was prepared with get.
+#[derive(Default)]
+pub struct PipelineCache {
+    shaders: HashMap<&'static str, wgpu::ShaderModule>,
+    layouts: HashMap<&'static str, wgpu::PipelineLayout>,
+    pipelines: HashMap<PipelineKey, Arc<wgpu::RenderPipeline>>,
+}
+
+impl PipelineCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_shader(&mut self, device: &wgpu::Device, id: &'static str, wgsl: &'static str) {
+        let module = device.create_shader_module(wgpu::ShaderModuleDescriptor {
+            label: Some(id),
+            source: wgpu::ShaderSource::Wgsl(wgsl.into()),
+        });
+        self.shaders.insert(id, module);
+        // pipelines built from the old module are stale
+        self.pipelines.retain(|key, _| key.shader != id);
+    }
+
+    pub fn add_layout(&mut self, id: &'static str, layout: wgpu::PipelineLayout) {
+        self.layouts.insert(id, layout);
+        self.pipelines.retain(|key, _| key.layout != id);
+    }
+
+    pub fn get_or_create(&mut self, device: &wgpu::Device, key: &PipelineKey) -> Arc<wgpu::RenderPipeline> {
+        if let Some(pipeline) = self.pipelines.get(key) {
+            return pipeline.clone();
+        }
+        let module = self.shaders.get(key.shader).unwrap_or_else(|| panic!("unknown shader {:?}", key.shader));
+        let layout = self.layouts.get(key.layout).unwrap_or_else(|| panic!("unknown pipeline layout {:?}", key.layout));
+        let mut builder = PipelineBuilder::with_module(module, key.color_format)
+            .label(key.shader)
+            .layout(layout)
+            .entry_points("vs_main", key.fs_entry)
+            .vertex_layouts(&key.vertex_layouts)
+            .blend(key.blend)
+            .cull_mode(key.cull_mode)
+            .front_face(key.front_face)
+            .topology(key.topology)
+            .polygon_mode(key.polygon_mode)
+            .depth_write(key.depth_write)
+            .sample_count(key.sample_count);
+        if let Some(depth) = key.depth {
+            builder = builder.depth(depth);
+        }
+        if let Some(compare) = key.depth_compare {
+            builder = builder.depth_compare(compare);
+        }
+        if let Some(format) = key.strip_index_format {
+            builder = builder.strip_index_format(format);
+        }
+        let pipeline = Arc::new(builder.build(device));
+        self.pipelines.insert(key.clone(), pipeline.clone());
+        pipeline
+    }
+
+    pub fn get(&self, key: &PipelineKey) -> Option<&wgpu::RenderPipeline> {
+        self.pipelines.get(key).map(|pipeline| pipeline.as_ref())
+    }
+
+    // Drops every pipeline none of `keys` describes, such as variants for a
+    // sample count or depth mode that is no longer used.
+    pub fn retain(&mut self, keys: &[PipelineKey]) {
+        self.pipelines.retain(|key, _| keys.contains(key));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // pipelines need a device, but everything the builder decides on its
+    // own can be checked without one
+    fn builder() -> PipelineBuilder<'static> {
+        let shader = wgpu::ShaderModuleDescriptor {
+            label: None,
+            source: wgpu::ShaderSource::Wgsl(include_str!("triangle-strip.wgsl").into()),
+        };
+        PipelineBuilder::new(shader, wgpu::TextureFormat::Rgba8UnormSrgb)
+    }
+
+    #[test]
+    fn blend_presets() {
+        assert_eq!(BlendMode::Replace.state(), wgpu::BlendState::REPLACE);
+        assert_eq!(BlendMode::Alpha.state().color.dst_factor, wgpu::BlendFactor::OneMinusSrcAlpha);
+        assert_eq!(BlendMode::Additive.state().color.src_factor, wgpu::BlendFactor::SrcAlpha);
+        assert_eq!(BlendMode::Additive.state().color.dst_factor, wgpu::BlendFactor::One);
+        assert_eq!(BlendMode::Premultiplied.state().color.src_factor, wgpu::BlendFactor::One);
+        assert_eq!(BlendMode::Premultiplied.next(), BlendMode::Replace);
+    }
+
+    #[test]
+    fn strip_index_format_only_on_strips() {
+        let strip = builder()
+            .topology(wgpu::PrimitiveTopology::TriangleStrip)
+            .strip_index_format(wgpu::IndexFormat::Uint16)
+            .primitive_state(wgpu::Features::empty());
+        assert_eq!(strip.strip_index_format, Some(wgpu::IndexFormat::Uint16));
+        let list = builder()
+            .strip_index_format(wgpu::IndexFormat::Uint16)
+            .primitive_state(wgpu::Features::empty());
+        assert_eq!(list.strip_index_format, None);
+    }
+
+    #[test]
+    fn wireframe_needs_the_feature() {
+        let wireframe = builder().polygon_mode(wgpu::PolygonMode::Line);
+        assert_eq!(wireframe.primitive_state(wgpu::Features::empty()).polygon_mode, wgpu::PolygonMode::Fill);
+        assert_eq!(
+            wireframe.primitive_state(wgpu::Features::POLYGON_MODE_LINE).polygon_mode,
+            wgpu::PolygonMode::Line
+        );
+    }
+
+    #[test]
+    fn depth_defaults_follow_depth_mode() {
+        assert!(builder().depth_stencil_state().is_none());
+        let reverse = builder().depth(transforms::DepthMode::ReverseZ).depth_stencil_state().unwrap();
+        assert_eq!(reverse.format, wgpu::TextureFormat::Depth32Float);
+        assert_eq!(reverse.depth_compare, wgpu::CompareFunction::GreaterEqual);
+        assert!(reverse.depth_write_enabled);
+
+        let overlay = builder()
+            .depth(transforms::DepthMode::Standard)
+            .depth_compare(wgpu::CompareFunction::Always)
+            .depth_write(false)
+            .depth_bias(2, 1.5, 0.0)
+            .depth_stencil_state()
+            .unwrap();
+        assert_eq!(overlay.depth_compare, wgpu::CompareFunction::Always);
+        assert!(!overlay.depth_write_enabled);
+        assert_eq!(overlay.bias.constant, 2);
+    }
+
+    #[test]
+    fn cache_shares_equal_descriptions() {
+        let _guard = crate::golden::GPU.lock().unwrap_or_else(|e| e.into_inner());
+        let init = pollster::block_on(transforms::InitWgpu::init_headless(
+            1,
+            1,
+            &transforms::InitConfig::headless(),
+        ))
+        .unwrap();
+        let mut cache = PipelineCache::new();
+        cache.add_shader(&init.device, "strip", include_str!("triangle-strip.wgsl"));
+        cache.add_layout(
+            "empty",
+            init.device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+                label: None,
+                bind_group_layouts: &[],
+                push_constant_ranges: &[],
+            }),
+        );
+
+        let key = PipelineKey::new("strip", "empty", Vec::new(), init.config.format);
+        let first = cache.get_or_create(&init.device, &key);
+        let second = cache.get_or_create(&init.device, &key.clone());
+        assert!(Arc::ptr_eq(&first, &second));
+
+        let double_sided = PipelineKey { cull_mode: None, ..key.clone() };
+        let blended = PipelineKey { blend: BlendMode::Alpha, ..key.clone() };
+        assert!(!Arc::ptr_eq(&first, &cache.get_or_create(&init.device, &double_sided)));
+        cache.get_or_create(&init.device, &blended);
+        assert!(cache.get(&blended).is_some());
+
+        // stale variants go, the rest stay shared
+        cache.retain(&[key.clone(), blended.clone()]);
+        assert!(cache.get(&double_sided).is_none());
+        assert!(Arc::ptr_eq(&first, &cache.get_or_create(&init.device, &key)));
+
+        // replacing the shader drops everything built from it
+        cache.add_shader(&init.device, "strip", include_str!("triangle-strip.wgsl"));
+        assert!(cache.get(&key).is_none());
+        assert!(cache.get(&blended).is_none());
+    }
+}
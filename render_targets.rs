@@ -0,0 +1,138 @@
+use crate::texture;
+
+// Size-dependent attachments shared by the passes of a frame. They are only
+// recreated when the surface size, depth format or sample count changes.
+pub struct RenderTargets {
+    // multisampled when sample_count > 1, kept after the main pass so later
+    // passes can test against it, or sample it when single-sampled
+    depth: texture::Texture,
+    // color is drawn here and resolved into the frame when multisampling
+    msaa_color: Option<wgpu::TextureView>,
+    color_format: wgpu::TextureFormat,
+    depth_format: wgpu::TextureFormat,
+    sample_count: u32,
+    width: u32,
+    height: u32,
+}
+
+impl RenderTargets {
+    pub fn new(
+        device: &wgpu::Device,
+        config: &wgpu::SurfaceConfiguration,
+        depth_format: wgpu::TextureFormat,
+        sample_count: u32,
+    ) -> Self {
+        let (width, height) = (config.width.max(1), config.height.max(1));
+        Self {
+            depth: texture::Texture::create_depth_texture(device, width, height, depth_format, sample_count, "depth"),
+            msaa_color: create_msaa_color(device, width, height, config.format, sample_count),
+            color_format: config.format,
+            depth_format,
+            sample_count,
+            width,
+            height,
+        }
+    }
+
+    pub fn resize(&mut self, device: &wgpu::Device, width: u32, height: u32) {
+        if (width, height) != (self.width, self.height) && width > 0 && height > 0 {
+            self.width = width;
+            self.height = height;
+            self.recreate(device);
+        }
+    }
+
+    pub fn set_depth_format(&mut self, device: &wgpu::Device, depth_format: wgpu::TextureFormat) {
+        if depth_format != self.depth_format {
+            self.depth_format = depth_format;
+            self.recreate(device);
+        }
+    }
+
+    pub fn set_sample_count(&mut self, device: &wgpu::Device, sample_count: u32) {
+        if sample_count != self.sample_count {
+            self.sample_count = sample_count;
+            self.recreate(device);
+        }
+    }
+
+    pub fn sample_count(&self) -> u32 {
+        self.sample_count
+    }
+
+    pub fn depth(&self) -> &texture::Texture {
+        &self.depth
+    }
+
+    // Color attachment that ends up in `frame`, going through the
+    // multisampled target when there is one.
+    pub fn color_attachment<'a>(
+        &'a self,
+        frame: &'a wgpu::TextureView,
+        clear: wgpu::Color,
+    ) -> wgpu::RenderPassColorAttachment<'a> {
+        let (view, resolve_target) = match &self.msaa_color {
+            Some(msaa) => (msaa, Some(frame)),
+            None => (frame, None),
+        };
+        wgpu::RenderPassColorAttachment {
+            view,
+            resolve_target,
+            ops: wgpu::Operations {
+                load: wgpu::LoadOp::Clear(clear),
+                // the resolved frame is what's kept, the samples can go
+                store: self.msaa_color.is_none(),
+            },
+        }
+    }
+
+    pub fn depth_attachment(&self, clear: f32) -> wgpu::RenderPassDepthStencilAttachment<'_> {
+        wgpu::RenderPassDepthStencilAttachment {
+            view: &self.depth.view,
+            depth_ops: Some(wgpu::Operations {
+                load: wgpu::LoadOp::Clear(clear),
+                store: true,
+            }),
+            stencil_ops: None,
+        }
+    }
+
+    fn recreate(&mut self, device: &wgpu::Device) {
+        self.depth = texture::Texture::create_depth_texture(
+            device,
+            self.width,
+            self.height,
+            self.depth_format,
+            self.sample_count,
+            "depth",
+        );
+        self.msaa_color = create_msaa_color(device, self.width, self.height, self.color_format, self.sample_count);
+    }
+}
+
+fn create_msaa_color(
+    device: &wgpu::Device,
+    width: u32,
+    height: u32,
+    format: wgpu::TextureFormat,
+    sample_count: u32,
+) -> Option<wgpu::TextureView> {
+    if sample_count <= 1 {
+        return None;
+    }
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("msaa color"),
+        size: wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count,
+        dimension: wgpu::TextureDimension::D2,
+        format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT,
+        view_formats: &[],
+    });
+    Some(texture.create_view(&wgpu::TextureViewDescriptor::default()))
+}
@@ -1,10 +1,13 @@
+use std::cell::RefCell;
 use std::io::{BufReader, Cursor};
 
 use anyhow::Ok;
+use base64::Engine;
 use cfg_if::cfg_if;
+use cgmath::{InnerSpace, Matrix4};
 use wgpu::util::DeviceExt;
 
-use crate::{model, texture};
+use crate::{model, pipeline, texture};
 
 #[cfg(target_arch = "wasm32")]
 fn format_url(file_name: &str) -> reqwest::Url {
@@ -18,6 +21,33 @@ fn format_url(file_name: &str) -> reqwest::Url {
     base.join(file_name).unwrap()
 }
 
+// Errors from the asset loaders, naming the file they came from.
+#[derive(Debug)]
+pub enum LoadError {
+    Read { file: String, source: anyhow::Error },
+    Parse { file: String, source: tobj::LoadError },
+    Texture { file: String, source: anyhow::Error },
+    Gltf { file: String, source: anyhow::Error },
+    // a material or mesh lacks data the renderer needs
+    MissingField { file: String, item: String, field: &'static str },
+}
+
+impl std::fmt::Display for LoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoadError::Read { file, source } => write!(f, "failed to read {}: {}", file, source),
+            LoadError::Parse { file, source } => write!(f, "failed to parse {}: {}", file, source),
+            LoadError::Texture { file, source } => write!(f, "failed to load texture {}: {}", file, source),
+            LoadError::Gltf { file, source } => write!(f, "failed to parse {}: {}", file, source),
+            LoadError::MissingField { file, item, field } => {
+                write!(f, "{}: {:?} has no {}", file, item, field)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
 pub async fn load_string(file_name: &str) -> anyhow::Result<String> {
     cfg_if! {
         if #[cfg(target_arch = "wasm32")] {
@@ -60,23 +90,76 @@ pub async fn load_binary(file_name: &str) -> anyhow::Result<Vec<u8>> {
 
 pub async fn load_texture(
     file_name: &str,
+    linear: bool,
     device: &wgpu::Device,
     queue: &wgpu::Queue,
 ) -> anyhow::Result<texture::Texture> {
-    let data = load_binary(file_name).await?;
-    texture::Texture::from_bytes(device, queue, &data, file_name)
+    let data = load_binary(file_name).await.map_err(|source| LoadError::Read {
+        file: file_name.to_string(),
+        source,
+    })?;
+    texture::Texture::from_bytes(device, queue, &data, file_name, linear).map_err(|source| {
+        LoadError::Texture {
+            file: file_name.to_string(),
+            source,
+        }
+        .into()
+    })
 }
 
-pub async fn load_model(
-    file_name: &str,
-    device: &wgpu::Device,
-    queue: &wgpu::Queue,
-    layout: &wgpu::BindGroupLayout,
-) -> anyhow::Result<model::Model> {
-    let obj_text = load_string(file_name).await?;
+// An equirectangular Radiance .hdr image for State::set_environment, in
+// linear light.
+pub async fn load_hdr(file_name: &str) -> anyhow::Result<image::Rgba32FImage> {
+    let data = load_binary(file_name).await.map_err(|source| LoadError::Read {
+        file: file_name.to_string(),
+        source,
+    })?;
+    let texture_error = |source: image::ImageError| LoadError::Texture {
+        file: file_name.to_string(),
+        source: source.into(),
+    };
+    // DynamicImage conversions clamp to 1, the decoder keeps the full range
+    let decoder = image::codecs::hdr::HdrDecoder::new(std::io::Cursor::new(data)).map_err(texture_error)?;
+    let meta = decoder.metadata();
+    let pixels = decoder.read_image_hdr().map_err(texture_error)?;
+    let rgba = pixels.iter().flat_map(|p| [p[0], p[1], p[2], 1.0]).collect();
+    Ok(image::Rgba32FImage::from_raw(meta.width, meta.height, rgba).unwrap())
+}
+
+// CPU-side vertices of every mesh in an OBJ or glTF/GLB file, e.g. for
+// instancing::mesh_vertex_layout.
+pub async fn load_vertices(file_name: &str) -> anyhow::Result<Vec<model::ModelVertex>> {
+    if file_name.ends_with(".gltf") || file_name.ends_with(".glb") {
+        let data = parse_gltf(file_name).await?;
+        let world = model::world_transforms(&data.nodes);
+        let vertices = data.meshes.into_iter().flat_map(|m| {
+            let transform = world[m.node];
+            m.vertices.into_iter().map(move |mut v| {
+                v.position = (transform * cgmath::Point3::from(v.position).to_homogeneous()).truncate().into();
+                v
+            })
+        });
+        return Ok(vertices.collect());
+    }
+    let (models, _) = load_obj(file_name).await?;
+    let mut vertices = Vec::new();
+    for m in &models {
+        vertices.extend(model_vertices(file_name, m)?);
+    }
+    Ok(vertices)
+}
+
+// Parses an OBJ file and the MTL files it references. A failing MTL is
+// reported by its own name rather than the OBJ's.
+async fn load_obj(file_name: &str) -> anyhow::Result<(Vec<tobj::Model>, Vec<tobj::Material>)> {
+    let obj_text = load_string(file_name).await.map_err(|source| LoadError::Read {
+        file: file_name.to_string(),
+        source,
+    })?;
     let obj_cursor = Cursor::new(obj_text);
     let mut obj_reader = BufReader::new(obj_cursor);
 
+    let mtl_error = RefCell::new(None);
     let (models, obj_materials) = tobj::load_obj_buf_async(
         &mut obj_reader,
         &tobj::LoadOptions {
@@ -84,66 +167,213 @@ pub async fn load_model(
             single_index: true,
             ..Default::default()
         },
-        |p| async move {
-            let mat_text = load_string(&p).await.unwrap();
-            tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mat_text)))
+        |p| {
+            let mtl_error = &mtl_error;
+            async move {
+                let mat_text = match load_string(&p).await {
+                    std::result::Result::Ok(text) => text,
+                    Err(source) => {
+                        mtl_error.replace(Some(LoadError::Read { file: p, source }));
+                        return Err(tobj::LoadError::OpenFileFailed);
+                    }
+                };
+                let result = tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mat_text)));
+                if let Err(source) = &result {
+                    mtl_error.replace(Some(LoadError::Parse { file: p, source: *source }));
+                }
+                result
+            }
         },
     )
-    .await?;
+    .await
+    .map_err(|source| LoadError::Parse {
+        file: file_name.to_string(),
+        source,
+    })?;
 
-    let mut materials = Vec::new();
-    for m in obj_materials? {
-        let diffuse_texture = load_texture(&m.diffuse_texture.unwrap(), device, queue).await?;
-        let normal_texture = load_texture(&m.normal_texture.unwrap(), device, queue).await?;
-        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
-            layout,
-            entries: &[
-                wgpu::BindGroupEntry {
-                    binding: 0,
-                    resource: wgpu::BindingResource::TextureView(&diffuse_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 1,
-                    resource: wgpu::BindingResource::Sampler(&diffuse_texture.sampler),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 2,
-                    resource: wgpu::BindingResource::TextureView(&normal_texture.view),
-                },
-                wgpu::BindGroupEntry {
-                    binding: 3,
-                    resource: wgpu::BindingResource::Sampler(&normal_texture.sampler),
-                },
-            ],
-            label: None,
-        });
+    let obj_materials = obj_materials.map_err(|source| {
+        mtl_error.take().unwrap_or(LoadError::Parse {
+            file: file_name.to_string(),
+            source,
+        })
+    })?;
+    Ok((models, obj_materials))
+}
 
-        materials.push(model::Material {
-            name: m.name,
-            diffuse_texture,
-            normal_texture,
-            bind_group,
+// tobj has no fields for Ke or the PBR extension (Pr, Pm, map_Ke), they end
+// up with the unknown parameters
+fn unknown_floats(m: &tobj::Material, key: &str) -> Option<Vec<f32>> {
+    m.unknown_param.get(key)?.split_whitespace().map(str::parse::<f32>).collect::<Result<Vec<_>, _>>().ok()
+}
+
+// MTL files that use the PBR extension are shaded as metallic-roughness.
+fn shading_model(m: &tobj::Material) -> model::ShadingModel {
+    if m.unknown_param.contains_key("Pr") || m.unknown_param.contains_key("Pm") {
+        model::ShadingModel::MetallicRoughness
+    } else {
+        model::ShadingModel::BlinnPhong
+    }
+}
+
+// Scalar MTL properties; anything the file leaves out keeps the
+// MaterialUniform default.
+fn material_uniform(m: &tobj::Material) -> model::MaterialUniform {
+    let defaults = model::MaterialUniform::default();
+    let color = |c: Option<[f32; 3]>, default: [f32; 4]| match c {
+        Some([r, g, b]) => [r, g, b, 1.0],
+        None => default,
+    };
+    let emissive = unknown_floats(m, "Ke").and_then(|values| match values[..] {
+        [r, g, b] => Some([r, g, b]),
+        _ => None,
+    });
+    let scalar = |key, default| match unknown_floats(m, key).as_deref() {
+        Some([value]) => *value,
+        _ => default,
+    };
+
+    model::MaterialUniform {
+        ambient_color: color(m.ambient, defaults.ambient_color),
+        diffuse_color: color(m.diffuse, defaults.diffuse_color),
+        specular_color: color(m.specular, defaults.specular_color),
+        emissive_color: color(emissive, defaults.emissive_color),
+        shininess: m.shininess.unwrap_or(defaults.shininess),
+        optical_density: m.optical_density.unwrap_or(defaults.optical_density),
+        dissolve: m.dissolve.unwrap_or(defaults.dissolve),
+        metallic: scalar("Pm", defaults.metallic),
+        roughness: scalar("Pr", defaults.roughness),
+        occlusion_strength: defaults.occlusion_strength,
+        _padding: [0.0; 2],
+    }
+}
+
+fn model_vertices(file_name: &str, m: &tobj::Model) -> anyhow::Result<Vec<model::ModelVertex>> {
+    let missing = |field| LoadError::MissingField {
+        file: file_name.to_string(),
+        item: m.name.clone(),
+        field,
+    };
+    if m.mesh.texcoords.is_empty() {
+        return Err(missing("texture coordinates (vt)").into());
+    }
+    if m.mesh.normals.is_empty() {
+        return Err(missing("normals (vn)").into());
+    }
+
+    let mut vertices = (0..m.mesh.positions.len() / 3)
+        .map(|i| model::ModelVertex {
+            position: [
+                m.mesh.positions[i * 3],
+                m.mesh.positions[i * 3 + 1],
+                m.mesh.positions[i * 3 + 2],
+            ],
+            tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]],
+            normal: [
+                m.mesh.normals[i * 3],
+                m.mesh.normals[i * 3 + 1],
+                m.mesh.normals[i * 3 + 2],
+            ],
+            tangent: [0.0; 3],
+            bitangent: [0.0; 3],
         })
+        .collect::<Vec<_>>();
+    compute_tangents(&mut vertices, &m.mesh.indices);
+    Ok(vertices)
+}
+
+// Per-vertex tangent frames from positions and UVs, in the spirit of
+// MikkTSpace: triangle tangents are accumulated on their vertices, then
+// made orthogonal to the vertex normal. The bitangent keeps the handedness
+// of the UV mapping, so mirrored UVs still light correctly.
+pub fn compute_tangents(vertices: &mut [model::ModelVertex], indices: &[u32]) {
+    use cgmath::Vector3;
+
+    let mut tangents = vec![Vector3::new(0.0f32, 0.0, 0.0); vertices.len()];
+    let mut bitangents = vec![Vector3::new(0.0f32, 0.0, 0.0); vertices.len()];
+    for tri in indices.chunks_exact(3) {
+        let [i0, i1, i2] = [tri[0] as usize, tri[1] as usize, tri[2] as usize];
+        let (v0, v1, v2) = (&vertices[i0], &vertices[i1], &vertices[i2]);
+        let p0 = Vector3::from(v0.position);
+        let edge1 = Vector3::from(v1.position) - p0;
+        let edge2 = Vector3::from(v2.position) - p0;
+        let duv1 = [v1.tex_coords[0] - v0.tex_coords[0], v1.tex_coords[1] - v0.tex_coords[1]];
+        let duv2 = [v2.tex_coords[0] - v0.tex_coords[0], v2.tex_coords[1] - v0.tex_coords[1]];
+
+        let det = duv1[0] * duv2[1] - duv2[0] * duv1[1];
+        if det.abs() < f32::EPSILON {
+            // degenerate UVs, leave it to the fallback below
+            continue;
+        }
+        let r = 1.0 / det;
+        let tangent = (edge1 * duv2[1] - edge2 * duv1[1]) * r;
+        let bitangent = (edge2 * duv1[0] - edge1 * duv2[0]) * r;
+        for i in [i0, i1, i2] {
+            tangents[i] += tangent;
+            bitangents[i] += bitangent;
+        }
+    }
+
+    for (i, v) in vertices.iter_mut().enumerate() {
+        let n = Vector3::from(v.normal).normalize();
+        // Gram-Schmidt against the normal
+        let mut t = tangents[i] - n * n.dot(tangents[i]);
+        if t.magnitude2() < f32::EPSILON {
+            // no usable UVs: any direction perpendicular to the normal will do
+            let axis = if n.x.abs() < 0.9 { Vector3::unit_x() } else { Vector3::unit_y() };
+            t = axis - n * n.dot(axis);
+        }
+        let t = t.normalize();
+        let handedness = if n.cross(t).dot(bitangents[i]) < 0.0 { -1.0 } else { 1.0 };
+        v.tangent = t.into();
+        v.bitangent = (n.cross(t) * handedness).into();
+    }
+}
+
+// Loads an OBJ file, or a glTF/GLB file by its extension.
+pub async fn load_model(
+    file_name: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+) -> anyhow::Result<model::Model> {
+    if file_name.ends_with(".gltf") || file_name.ends_with(".glb") {
+        return load_gltf(file_name, device, queue, layout).await;
+    }
+    let (models, obj_materials) = load_obj(file_name).await?;
+
+    let mut materials = Vec::new();
+    for m in obj_materials {
+        let mut textures = model::MaterialTextures::defaults(device, queue)?;
+        if let Some(file) = &m.diffuse_texture {
+            textures.diffuse = load_texture(file, false, device, queue).await?;
+        }
+        if let Some(file) = &m.normal_texture {
+            textures.normal = load_texture(file, true, device, queue).await?;
+        }
+        if let Some(file) = m.unknown_param.get("map_Ke") {
+            textures.emissive = load_texture(file, false, device, queue).await?;
+        }
+        let uniform = material_uniform(&m);
+
+        let mut material = model::Material::new(device, &m.name, textures, uniform, layout);
+        material.shading_model = shading_model(&m);
+        materials.push(material);
+    }
+    // meshes without a usemtl still need something to bind
+    if materials.is_empty() {
+        materials.push(model::Material::new(
+            device,
+            "default",
+            model::MaterialTextures::defaults(device, queue)?,
+            model::MaterialUniform::default(),
+            layout,
+        ));
     }
 
     let meshes = models
         .into_iter()
         .map(|m| {
-            let vertices = (0..m.mesh.positions.len() / 3)
-                .map(|i| model::ModelVertex {
-                    position: [
-                        m.mesh.positions[i * 3],
-                        m.mesh.positions[i * 3 + 1],
-                        m.mesh.positions[i * 3 + 2],
-                    ],
-                    tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]],
-                    normal: [
-                        m.mesh.normals[i * 3],
-                        m.mesh.normals[i * 3 + 1],
-                        m.mesh.normals[i * 3 + 2],
-                    ],
-                })
-                .collect::<Vec<_>>();
+            let vertices = model_vertices(file_name, &m)?;
 
             let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
                 label: Some(&format!("{:?} Vertex Buffer", file_name)),
@@ -156,17 +386,22 @@ pub async fn load_model(
                 usage: wgpu::BufferUsages::INDEX,
             });
 
-            model::Mesh {
+            Ok(model::Mesh {
                 name: file_name.to_string(),
                 vertex_buffer,
                 index_buffer,
                 num_elements: m.mesh.indices.len() as u32,
                 material: m.mesh.material_id.unwrap_or(0),
-            }
+                node: 0,
+            })
         })
-        .collect::<Vec<_>>();
+        .collect::<anyhow::Result<Vec<_>>>()?;
 
-    Ok(model::Model { meshes, materials })
+    Ok(model::Model {
+        meshes,
+        materials,
+        nodes: vec![model::Node::root()],
+    })
 }
 #[allow(unused)]
 pub async fn load_model_raw(
@@ -174,47 +409,16 @@ pub async fn load_model_raw(
     device: &wgpu::Device,
     queue: &wgpu::Queue,
     layout: &wgpu::BindGroupLayout,
-) -> (Vec<model::ModelRaw>, Vec<u32>) {
-    let obj_text = load_string(file_name).await.unwrap();
-    let obj_cursor = Cursor::new(obj_text);
-    let mut obj_reader = BufReader::new(obj_cursor);
-
-    let (models, obj_materials) = tobj::load_obj_buf_async(
-        &mut obj_reader,
-        &tobj::LoadOptions {
-            triangulate: true,
-            single_index: true,
-            ..Default::default()
-        },
-        |p| async move {
-            let mat_text = load_string(&p).await.unwrap();
-            tobj::load_mtl_buf(&mut BufReader::new(Cursor::new(mat_text)))
-        },
-    )
-    .await.unwrap();
+) -> anyhow::Result<(Vec<model::ModelRaw>, Vec<u32>)> {
+    let (models, _obj_materials) = load_obj(file_name).await?;
 
     let mut idx = Vec::new();
     let mut vd = Vec::new();
-    models.iter().for_each(|m| {
-        let vertices = (0..m.mesh.positions.len() / 3)
-            .map(|i| model::ModelVertex {
-                position: [
-                    m.mesh.positions[i * 3],
-                    m.mesh.positions[i * 3 + 1],
-                    m.mesh.positions[i * 3 + 2],
-                ],
-                tex_coords: [m.mesh.texcoords[i * 2], m.mesh.texcoords[i * 2 + 1]],
-                normal: [
-                    m.mesh.normals[i * 3],
-                    m.mesh.normals[i * 3 + 1],
-                    m.mesh.normals[i * 3 + 2],
-                ],
-            }.to_raw())
-            .collect::<Vec<_>>();
-        vd.extend(vertices);
+    for m in &models {
+        let vertices = model_vertices(file_name, m)?;
+        vd.extend(vertices.iter().map(model::ModelVertex::to_raw));
         idx.extend(m.mesh.indices.clone());
-        
-    });
+    }
     // for m in obj_materials.unwrap() {
     //     let tx = load_texture(&m.diffuse_texture.unwrap(), device, queue).await.unwrap();
     //     tx.
@@ -229,14 +433,499 @@ pub async fn load_model_raw(
     //         normal: [n[0], n[1], n[2], 1.0],
     //     }
     // }).collect::<Vec<ModelRaw>>();
-    // let x = obj_materials.unwrap().into_iter().map(|x| {
-    //     light(
-    //         x.specular.or_else(|| {Some([1.0, 1.0, 0.0])}).unwrap(), 
-    //         x.ambient.or_else(Some(0.1)).unwrap(), 
-    //         x.diffuse.or_else(Some(0.6)).unwrap(), 
-    //         x.optical_density.or_else(Some(0.3)).unwrap(), 
-    //         x.shininess.or_else(Some(32.0)).unwrap()
-    //     )
-    // });
-    (vd, idx)
-}
\ No newline at end of file
+    Ok((vd, idx))
+}
+// Geometry and materials of a glTF file, decoded but not yet uploaded.
+pub struct GltfData {
+    pub meshes: Vec<GltfMesh>,
+    pub materials: Vec<GltfMaterial>,
+    // nodes of the scene, parents first
+    pub nodes: Vec<model::Node>,
+}
+
+// One primitive in the space of the node that uses it.
+pub struct GltfMesh {
+    pub name: String,
+    pub vertices: Vec<model::ModelVertex>,
+    pub indices: Vec<u32>,
+    pub material: usize,
+    pub node: usize,
+}
+
+pub struct GltfMaterial {
+    pub name: String,
+    pub diffuse: Option<image::DynamicImage>,
+    pub normal: Option<image::DynamicImage>,
+    pub metallic_roughness: Option<image::DynamicImage>,
+    pub occlusion: Option<image::DynamicImage>,
+    pub emissive: Option<image::DynamicImage>,
+    pub uniform: model::MaterialUniform,
+    pub double_sided: bool,
+    // alphaMode BLEND; MASK is drawn opaque for now
+    pub blend: pipeline::BlendMode,
+}
+
+// Loads a .gltf or .glb file into the same structures load_model builds from
+// OBJ/MTL, with metallic-roughness shading. Missing textures fall back to
+// the defaults.
+async fn load_gltf(
+    file_name: &str,
+    device: &wgpu::Device,
+    queue: &wgpu::Queue,
+    layout: &wgpu::BindGroupLayout,
+) -> anyhow::Result<model::Model> {
+    let data = parse_gltf(file_name).await?;
+
+    let mut materials = Vec::new();
+    for m in data.materials {
+        let mut textures = model::MaterialTextures::defaults(device, queue)?;
+        // only base color and emissive hold colors
+        let maps = [
+            (&m.diffuse, &mut textures.diffuse, false),
+            (&m.normal, &mut textures.normal, true),
+            (&m.metallic_roughness, &mut textures.metallic_roughness, true),
+            (&m.occlusion, &mut textures.occlusion, true),
+            (&m.emissive, &mut textures.emissive, false),
+        ];
+        for (img, texture, linear) in maps {
+            if let Some(img) = img {
+                *texture = texture::Texture::from_image(device, queue, img, Some(&m.name), linear)?;
+            }
+        }
+        let mut material = model::Material::new(device, &m.name, textures, m.uniform, layout);
+        material.double_sided = m.double_sided;
+        material.blend = m.blend;
+        material.shading_model = model::ShadingModel::MetallicRoughness;
+        materials.push(material);
+    }
+
+    let meshes = data
+        .meshes
+        .into_iter()
+        .map(|m| {
+            let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{:?} Vertex Buffer", m.name)),
+                contents: bytemuck::cast_slice(&m.vertices),
+                usage: wgpu::BufferUsages::VERTEX,
+            });
+            let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+                label: Some(&format!("{:?} Index Buffer", m.name)),
+                contents: bytemuck::cast_slice(&m.indices),
+                usage: wgpu::BufferUsages::INDEX,
+            });
+
+            model::Mesh {
+                name: m.name,
+                vertex_buffer,
+                index_buffer,
+                num_elements: m.indices.len() as u32,
+                material: m.material,
+                node: m.node,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    Ok(model::Model {
+        meshes,
+        materials,
+        nodes: data.nodes,
+    })
+}
+
+pub async fn parse_gltf(file_name: &str) -> anyhow::Result<GltfData> {
+    let bytes = load_binary(file_name).await.map_err(|source| LoadError::Read {
+        file: file_name.to_string(),
+        source,
+    })?;
+    let gltf = gltf::Gltf::from_slice(&bytes).map_err(|source| LoadError::Gltf {
+        file: file_name.to_string(),
+        source: source.into(),
+    })?;
+
+    let mut buffers = Vec::new();
+    for buffer in gltf.document.buffers() {
+        let data = match buffer.source() {
+            gltf::buffer::Source::Bin => gltf.blob.clone().ok_or_else(|| LoadError::MissingField {
+                file: file_name.to_string(),
+                item: format!("buffer {}", buffer.index()),
+                field: "binary chunk",
+            })?,
+            gltf::buffer::Source::Uri(uri) => load_uri(file_name, uri).await?,
+        };
+        buffers.push(data);
+    }
+
+    let mut materials = Vec::new();
+    for material in gltf.document.materials() {
+        let pbr = material.pbr_metallic_roughness();
+        let diffuse = match pbr.base_color_texture() {
+            Some(info) => Some(load_gltf_image(file_name, info.texture().source(), &buffers).await?),
+            None => None,
+        };
+        let normal = match material.normal_texture() {
+            Some(info) => Some(load_gltf_image(file_name, info.texture().source(), &buffers).await?),
+            None => None,
+        };
+        let metallic_roughness = match pbr.metallic_roughness_texture() {
+            Some(info) => Some(load_gltf_image(file_name, info.texture().source(), &buffers).await?),
+            None => None,
+        };
+        let occlusion = match material.occlusion_texture() {
+            Some(info) => Some((load_gltf_image(file_name, info.texture().source(), &buffers).await?, info.strength())),
+            None => None,
+        };
+        let emissive = match material.emissive_texture() {
+            Some(info) => Some(load_gltf_image(file_name, info.texture().source(), &buffers).await?),
+            None => None,
+        };
+        let [er, eg, eb] = material.emissive_factor();
+        let base_color = pbr.base_color_factor();
+        materials.push(GltfMaterial {
+            name: material
+                .name()
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("material {}", materials.len())),
+            diffuse,
+            normal,
+            metallic_roughness,
+            uniform: model::MaterialUniform {
+                diffuse_color: [base_color[0], base_color[1], base_color[2], 1.0],
+                emissive_color: [er, eg, eb, 1.0],
+                dissolve: base_color[3],
+                metallic: pbr.metallic_factor(),
+                roughness: pbr.roughness_factor(),
+                occlusion_strength: occlusion.as_ref().map_or(1.0, |(_, strength)| *strength),
+                ..Default::default()
+            },
+            occlusion: occlusion.map(|(img, _)| img),
+            emissive,
+            double_sided: material.double_sided(),
+            blend: match material.alpha_mode() {
+                gltf::material::AlphaMode::Blend => pipeline::BlendMode::Alpha,
+                _ => pipeline::BlendMode::Replace,
+            },
+        });
+    }
+    // primitives without a material use the glTF default material, appended last
+    let default_material = materials.len();
+
+    let mut meshes = Vec::new();
+    let mut nodes = Vec::new();
+    let scene = gltf.document.default_scene().or_else(|| gltf.document.scenes().next());
+    match scene {
+        Some(scene) => {
+            for node in scene.nodes() {
+                read_node(file_name, &node, None, &buffers, default_material, &mut nodes, &mut meshes)?;
+            }
+        }
+        None => {
+            nodes.push(model::Node::root());
+            for mesh in gltf.document.meshes() {
+                read_mesh(file_name, &mesh, mesh.name(), 0, &buffers, default_material, &mut meshes)?;
+            }
+        }
+    }
+
+    if meshes.iter().any(|m| m.material == default_material) {
+        materials.push(GltfMaterial {
+            name: "default".to_string(),
+            diffuse: None,
+            normal: None,
+            metallic_roughness: None,
+            occlusion: None,
+            emissive: None,
+            // the glTF default material is fully metallic and rough
+            uniform: model::MaterialUniform {
+                metallic: 1.0,
+                roughness: 1.0,
+                ..Default::default()
+            },
+            double_sided: false,
+            blend: pipeline::BlendMode::Replace,
+        });
+    }
+
+    Ok(GltfData { meshes, materials, nodes })
+}
+
+fn read_node(
+    file_name: &str,
+    node: &gltf::Node,
+    parent: Option<usize>,
+    buffers: &[Vec<u8>],
+    default_material: usize,
+    nodes: &mut Vec<model::Node>,
+    meshes: &mut Vec<GltfMesh>,
+) -> anyhow::Result<()> {
+    let index = nodes.len();
+    nodes.push(model::Node {
+        transform: Matrix4::from(node.transform().matrix()),
+        parent,
+    });
+    if let Some(mesh) = node.mesh() {
+        let name = node.name().or(mesh.name());
+        read_mesh(file_name, &mesh, name, index, buffers, default_material, meshes)?;
+    }
+    for child in node.children() {
+        read_node(file_name, &child, Some(index), buffers, default_material, nodes, meshes)?;
+    }
+    Ok(())
+}
+
+fn read_mesh(
+    file_name: &str,
+    mesh: &gltf::Mesh,
+    name: Option<&str>,
+    node: usize,
+    buffers: &[Vec<u8>],
+    default_material: usize,
+    meshes: &mut Vec<GltfMesh>,
+) -> anyhow::Result<()> {
+    let name = name
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{} mesh {}", file_name, mesh.index()));
+
+    for primitive in mesh.primitives() {
+        if primitive.mode() != gltf::mesh::Mode::Triangles {
+            log::warn!("{}: skipping {:?} primitive in {:?}", file_name, primitive.mode(), name);
+            continue;
+        }
+        let missing = |field| LoadError::MissingField {
+            file: file_name.to_string(),
+            item: name.clone(),
+            field,
+        };
+        let reader = primitive.reader(|buffer| buffers.get(buffer.index()).map(Vec::as_slice));
+        let positions = reader.read_positions().ok_or_else(|| missing("positions (POSITION)"))?;
+        let normals = reader
+            .read_normals()
+            .ok_or_else(|| missing("normals (NORMAL)"))?
+            .collect::<Vec<_>>();
+        let tex_coords = reader
+            .read_tex_coords(0)
+            .map(|tc| tc.into_f32().collect::<Vec<_>>())
+            .unwrap_or_default();
+        let tangents = reader.read_tangents().map(|t| t.collect::<Vec<_>>());
+
+        let mut vertices = positions
+            .enumerate()
+            .map(|(i, position)| {
+                let normal = normals.get(i).copied().unwrap_or([0.0, 0.0, 1.0]);
+                model::ModelVertex {
+                    position,
+                    tex_coords: tex_coords.get(i).copied().unwrap_or([0.0, 0.0]),
+                    normal: cgmath::Vector3::from(normal).normalize().into(),
+                    tangent: [0.0; 3],
+                    bitangent: [0.0; 3],
+                }
+            })
+            .collect::<Vec<_>>();
+        let indices: Vec<u32> = match reader.read_indices() {
+            Some(indices) => indices.into_u32().collect(),
+            None => (0..vertices.len() as u32).collect(),
+        };
+        match tangents {
+            // glTF tangents are xyz plus the bitangent sign in w
+            Some(tangents) => {
+                for (v, [x, y, z, w]) in vertices.iter_mut().zip(tangents) {
+                    let n = cgmath::Vector3::from(v.normal);
+                    let t = cgmath::Vector3::new(x, y, z).normalize();
+                    v.tangent = t.into();
+                    v.bitangent = (n.cross(t) * w).into();
+                }
+            }
+            None => compute_tangents(&mut vertices, &indices),
+        }
+
+        meshes.push(GltfMesh {
+            name: name.clone(),
+            vertices,
+            indices,
+            material: primitive.material().index().unwrap_or(default_material),
+            node,
+        });
+    }
+    Ok(())
+}
+
+async fn load_gltf_image(
+    file_name: &str,
+    image: gltf::Image<'_>,
+    buffers: &[Vec<u8>],
+) -> anyhow::Result<image::DynamicImage> {
+    let (bytes, label) = match image.source() {
+        gltf::image::Source::View { view, .. } => {
+            let bytes = buffers
+                .get(view.buffer().index())
+                .and_then(|buffer| buffer.get(view.offset()..view.offset() + view.length()))
+                .ok_or_else(|| LoadError::Gltf {
+                    file: file_name.to_string(),
+                    source: anyhow::anyhow!("buffer view {} of image {} is out of bounds", view.index(), image.index()),
+                })?;
+            (bytes.to_vec(), format!("{} image {}", file_name, image.index()))
+        }
+        gltf::image::Source::Uri { uri, .. } => (load_uri(file_name, uri).await?, uri.to_string()),
+    };
+    image::load_from_memory(&bytes).map_err(|source| {
+        LoadError::Texture {
+            file: label,
+            source: source.into(),
+        }
+        .into()
+    })
+}
+
+// Resolves a glTF URI: either an embedded base64 data URI or a file next to
+// the glTF file.
+async fn load_uri(file_name: &str, uri: &str) -> anyhow::Result<Vec<u8>> {
+    if let Some(data) = uri.strip_prefix("data:") {
+        let encoded = data.split_once(";base64,").map(|(_, encoded)| encoded).ok_or_else(|| {
+            LoadError::MissingField {
+                file: file_name.to_string(),
+                item: format!("{:.32}", uri),
+                field: "base64 payload",
+            }
+        })?;
+        return base64::engine::general_purpose::STANDARD.decode(encoded).map_err(|source| {
+            LoadError::Read {
+                file: file_name.to_string(),
+                source: source.into(),
+            }
+            .into()
+        });
+    }
+
+    let path = match file_name.rfind('/') {
+        Some(i) => format!("{}/{}", &file_name[..i], uri),
+        None => uri.to_string(),
+    };
+    load_binary(&path).await.map_err(|source| LoadError::Read { file: path, source }.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gltf_embedded_buffer_and_textures() {
+        let data = pollster::block_on(parse_gltf("gltf-triangle.gltf")).unwrap();
+        assert_eq!(data.meshes.len(), 1);
+        let mesh = &data.meshes[0];
+        assert_eq!(mesh.name, "triangle");
+        assert_eq!(mesh.indices, vec![0, 1, 2]);
+        assert_eq!(mesh.vertices[1].position, [1.0, 0.0, 0.0]);
+        assert_eq!(mesh.vertices[2].tex_coords, [0.0, 1.0]);
+        assert_eq!(mesh.material, 0);
+
+        assert_eq!(data.materials.len(), 1);
+        let material = &data.materials[0];
+        assert_eq!(material.name, "red");
+        assert_eq!(material.uniform.diffuse_color, [1.0, 0.0, 0.0, 1.0]);
+        assert_eq!(material.uniform.dissolve, 0.5);
+        assert_eq!(material.uniform.emissive_color, [0.0, 0.5, 0.0, 1.0]);
+        // alpha below 1 only blends with alphaMode BLEND
+        assert_eq!(material.blend, pipeline::BlendMode::Replace);
+        assert!(!material.double_sided);
+        assert_eq!(material.diffuse.as_ref().map(|img| img.width()), Some(2));
+        assert_eq!(material.normal.as_ref().map(|img| img.width()), Some(1));
+        assert_eq!(material.uniform.metallic, 0.25);
+        assert_eq!(material.uniform.roughness, 0.75);
+        assert_eq!(material.uniform.occlusion_strength, 1.0);
+        assert!(material.metallic_roughness.is_none());
+    }
+
+    #[test]
+    fn mtl_pbr_extension_switches_shading_model() {
+        let mut m = tobj::Material::default();
+        assert_eq!(shading_model(&m), model::ShadingModel::BlinnPhong);
+        m.unknown_param.insert("Pm".to_string(), "0.8".to_string());
+        m.unknown_param.insert("Ke".to_string(), "0.1 0.2 0.3".to_string());
+        assert_eq!(shading_model(&m), model::ShadingModel::MetallicRoughness);
+        let uniform = material_uniform(&m);
+        assert_eq!(uniform.metallic, 0.8);
+        // Pr left out keeps the default
+        assert_eq!(uniform.roughness, model::MaterialUniform::default().roughness);
+        assert_eq!(uniform.emissive_color, [0.1, 0.2, 0.3, 1.0]);
+    }
+
+    #[test]
+    fn gltf_external_buffer_and_node_hierarchy() {
+        let data = pollster::block_on(parse_gltf("gltf-hierarchy.gltf")).unwrap();
+        // the child keeps its own transform below the parent
+        assert_eq!(data.nodes.len(), 2);
+        assert_eq!(data.nodes[0].parent, None);
+        assert_eq!(data.nodes[1].parent, Some(0));
+        let child = Matrix4::from_translation(cgmath::Vector3::new(0.0, 2.0, 0.0)) * Matrix4::from_scale(2.0);
+        assert_eq!(data.nodes[1].transform, child);
+        let world = model::world_transforms(&data.nodes);
+        assert_eq!(world[1], Matrix4::from_translation(cgmath::Vector3::new(1.0, 0.0, 0.0)) * child);
+
+        // one mesh per primitive, both in the child's space
+        assert_eq!(data.meshes.len(), 2);
+        for mesh in &data.meshes {
+            assert_eq!(mesh.name, "child");
+            assert_eq!(mesh.node, 1);
+            assert_eq!(mesh.vertices[0].position, [0.0, 0.0, 0.0]);
+            assert_eq!(mesh.vertices[1].position, [1.0, 0.0, 0.0]);
+            assert_eq!(mesh.vertices[2].position, [0.0, 1.0, 0.0]);
+            assert_eq!(mesh.vertices[0].normal, [0.0, 0.0, 1.0]);
+        }
+        // the primitive without indices gets sequential ones
+        assert_eq!(data.meshes[1].indices, vec![0, 1, 2]);
+
+        // the second primitive has no material and uses the appended default
+        assert_eq!(data.materials.len(), 2);
+        assert_eq!(data.meshes[0].material, 0);
+        assert_eq!(data.meshes[1].material, 1);
+        assert_eq!(data.materials[1].name, "default");
+        assert!(data.materials[0].diffuse.is_none());
+    }
+
+    #[test]
+    fn tangents_follow_uv_directions() {
+        let vertex = |position: [f32; 3], tex_coords: [f32; 2]| model::ModelVertex {
+            position,
+            tex_coords,
+            normal: [0.0, 0.0, 1.0],
+            tangent: [0.0; 3],
+            bitangent: [0.0; 3],
+        };
+        // u runs along +x, v along -y
+        let mut vertices = vec![
+            vertex([0.0, 0.0, 0.0], [0.0, 1.0]),
+            vertex([1.0, 0.0, 0.0], [1.0, 1.0]),
+            vertex([0.0, 1.0, 0.0], [0.0, 0.0]),
+        ];
+        compute_tangents(&mut vertices, &[0, 1, 2]);
+        for v in &vertices {
+            assert_eq!(v.tangent, [1.0, 0.0, 0.0]);
+            assert_eq!(v.bitangent, [0.0, -1.0, 0.0]);
+        }
+    }
+
+    #[test]
+    fn hdr_keeps_values_above_one() {
+        let sky = pollster::block_on(load_hdr("sky.hdr")).unwrap();
+        assert_eq!(sky.dimensions(), (64, 32));
+        let brightest = sky.pixels().map(|p| p[0]).fold(0.0, f32::max);
+        assert!(brightest > 1.0, "{}", brightest);
+    }
+
+    #[test]
+    fn gltf_image_past_end_of_buffer_is_an_error() {
+        let err = pollster::block_on(parse_gltf("gltf-truncated.gltf")).err().unwrap();
+        match err.downcast_ref::<LoadError>() {
+            Some(LoadError::Gltf { file, .. }) => assert_eq!(file, "gltf-truncated.gltf"),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn gltf_missing_file_names_the_file() {
+        let err = pollster::block_on(parse_gltf("missing.gltf")).err().unwrap();
+        match err.downcast_ref::<LoadError>() {
+            Some(LoadError::Read { file, .. }) => assert_eq!(file, "missing.gltf"),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,54 @@
+// Depth-only passes that render the instances from a light into one layer
+// of a shadow map array.
+struct ShadowPass {
+    // the light's view-projection times the global model matrix
+    light_model_mat: mat4x4<f32>,
+    model_mat: mat4x4<f32>,
+    // point lights: xyz position, w range
+    light: vec4<f32>,
+};
+
+@binding(0) @group(0) var<uniform> pass_uniforms: ShadowPass;
+
+struct InstanceInput {
+    @location(5) model_matrix_0: vec4<f32>,
+    @location(6) model_matrix_1: vec4<f32>,
+    @location(7) model_matrix_2: vec4<f32>,
+    @location(8) model_matrix_3: vec4<f32>,
+};
+
+fn instance_matrix(instance: InstanceInput) -> mat4x4<f32> {
+    return mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+}
+
+@vertex
+fn vs_main(@location(0) pos: vec4<f32>, instance: InstanceInput) -> @builtin(position) vec4<f32> {
+    return pass_uniforms.light_model_mat * instance_matrix(instance) * pos;
+}
+
+struct PointOutput {
+    @builtin(position) position: vec4<f32>,
+    @location(0) world_pos: vec3<f32>,
+};
+
+// one cube face of a point light
+@vertex
+fn vs_point(@location(0) pos: vec4<f32>, instance: InstanceInput) -> PointOutput {
+    var output: PointOutput;
+    let m_pos = instance_matrix(instance) * pos;
+    output.position = pass_uniforms.light_model_mat * m_pos;
+    output.world_pos = (pass_uniforms.model_mat * m_pos).xyz;
+    return output;
+}
+
+// Stores distance / range instead of the projected depth, so lookups
+// don't need to know which face the distance was measured along.
+@fragment
+fn fs_point(in: PointOutput) -> @builtin(frag_depth) f32 {
+    return clamp(distance(in.world_pos, pass_uniforms.light.xyz) / pass_uniforms.light.w, 0.0, 1.0);
+}
@@ -0,0 +1,657 @@
+use std::num::NonZeroU64;
+
+use cgmath::*;
+
+use crate::{camera::Camera, instancing, lights, model::{self, DrawModel, Vertex}, pipeline, transforms};
+
+// layers in the shadow map array, shared by the directional and spot
+// lights; keep in sync with lightning.wgsl
+pub const MAX_SHADOW_MAPS: usize = 8;
+pub const MAX_CASCADES: u32 = 4;
+// point lights with a cube of shadow maps, six layers each in their own array
+pub const MAX_POINT_SHADOWS: usize = 2;
+const POINT_LAYERS: usize = 6 * MAX_POINT_SHADOWS;
+// The GL backend turns square arrays with a multiple of six layers into cube
+// maps, which can't be rendered to layer by layer; one spare layer avoids it.
+// The faces are therefore sampled as a plain array and lightning.wgsl picks
+// the face for every PCF tap itself, which keeps filtering across seams.
+const POINT_TEXTURE_LAYERS: usize = POINT_LAYERS + 1;
+
+// Forward and up vector of every cube face, in the order the faces are
+// stored; lightning.wgsl picks faces with the same table.
+pub const CUBE_FACES: [([f32; 3], [f32; 3]); 6] = [
+    ([1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+    ([-1.0, 0.0, 0.0], [0.0, 1.0, 0.0]),
+    ([0.0, 1.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, -1.0, 0.0], [0.0, 0.0, 1.0]),
+    ([0.0, 0.0, 1.0], [0.0, 1.0, 0.0]),
+    ([0.0, 0.0, -1.0], [0.0, 1.0, 0.0]),
+];
+
+// Shadow maps use the standard depth layout whatever the main pass uses.
+const SHADOW_DEPTH: transforms::DepthMode = transforms::DepthMode::Standard;
+// 0 splits cascades evenly, 1 logarithmically
+const SPLIT_LAMBDA: f32 = 0.75;
+const SPOT_NEAR: f32 = 0.05;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ShadowSettings {
+    // width and height of every shadow map layer
+    pub map_size: u32,
+    // cascades per directional light, 1..=MAX_CASCADES
+    pub cascades: u32,
+    // directional shadows cover the view up to this far from the camera
+    pub distance: f32,
+    // hardware depth bias applied while drawing the shadow maps
+    pub depth_bias: i32,
+    pub slope_bias: f32,
+    // world units the lookup point is moved along the surface normal
+    pub normal_bias: f32,
+    // PCF kernel is (2 * radius + 1)^2 taps, 0 for a single bilinear tap
+    pub pcf_radius: u32,
+    // size of each cube face of the point light shadows
+    pub point_map_size: u32,
+    // world units point light lookups are moved towards the light; the
+    // hardware bias doesn't apply since those passes write their own depth
+    pub point_bias: f32,
+}
+
+impl Default for ShadowSettings {
+    fn default() -> Self {
+        Self {
+            map_size: 1024,
+            cascades: 3,
+            distance: 60.0,
+            depth_bias: 2,
+            slope_bias: 2.0,
+            normal_bias: 0.05,
+            pcf_radius: 1,
+            point_map_size: 512,
+            point_bias: 0.05,
+        }
+    }
+}
+
+// Matches the Shadows struct in lightning.wgsl.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct ShadowUniform {
+    light_view_proj: [[[f32; 4]; 4]; MAX_SHADOW_MAPS],
+    // normal bias, PCF radius, texel size in uv, point bias
+    params: [f32; 4],
+    // texel size of the point light faces, the rest is padding
+    point_params: [f32; 4],
+}
+
+// Matches the ShadowPass struct in shadow.wgsl, one per rendered layer.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+struct ShadowPassUniform {
+    light_model_mat: [[f32; 4]; 4],
+    model_mat: [[f32; 4]; 4],
+    // xyz position and range of a point light
+    light: [f32; 4],
+}
+
+// First layer and layer count for every light, None for lights without
+// shadows. Directional lights take `cascades` layers and spot lights one of
+// the MAX_SHADOW_MAPS; point lights take six of the point light layers.
+// Lights that don't fit any more get none.
+pub fn assign_layers(lights: &[lights::Light], cascades: u32) -> Vec<Option<(u32, u32)>> {
+    let (mut next, mut next_point) = (0, 0);
+    lights
+        .iter()
+        .map(|light| {
+            let (next, max, needed) = match light.kind {
+                _ if !light.cast_shadows => return None,
+                lights::LightKind::Directional { .. } => (&mut next, MAX_SHADOW_MAPS, cascades),
+                lights::LightKind::Spot { .. } => (&mut next, MAX_SHADOW_MAPS, 1),
+                lights::LightKind::Point { .. } => (&mut next_point, POINT_LAYERS, 6),
+            };
+            if *next + needed > max as u32 {
+                return None;
+            }
+            *next += needed;
+            Some((*next - needed, needed))
+        })
+        .collect()
+}
+
+// Distances from the camera where the cascades start and end, count + 1 of
+// them, blending even and logarithmic splits.
+pub fn cascade_splits(near: f32, far: f32, count: u32) -> Vec<f32> {
+    (0..=count)
+        .map(|i| {
+            let t = i as f32 / count as f32;
+            let linear = near + (far - near) * t;
+            let log = near * (far / near).powf(t);
+            SPLIT_LAMBDA * log + (1.0 - SPLIT_LAMBDA) * linear
+        })
+        .collect()
+}
+
+// Corners of the part of the view between `near` and `far` along the view
+// direction, in world space.
+fn frustum_slice(camera: &Camera, projection: &transforms::Projection, aspect: f32, near: f32, far: f32) -> [Point3<f32>; 8] {
+    let forward = (camera.direction - camera.position).normalize();
+    let right = forward.cross(camera.up).normalize();
+    let up = right.cross(forward);
+    let half_height = |distance: f32| match projection {
+        transforms::Projection::Orthographic { height, zoom, .. } => height / zoom * 0.5,
+        _ => distance * (projection.fovy().unwrap() / 2.0).tan(),
+    };
+    let mut corners = [Point3::origin(); 8];
+    for (i, distance) in [near, far].into_iter().enumerate() {
+        let center = camera.position + forward * distance;
+        let (h, w) = (half_height(distance), half_height(distance) * aspect);
+        corners[i * 4] = center + up * h - right * w;
+        corners[i * 4 + 1] = center + up * h + right * w;
+        corners[i * 4 + 2] = center - up * h - right * w;
+        corners[i * 4 + 3] = center - up * h + right * w;
+    }
+    corners
+}
+
+fn light_up(direction: Vector3<f32>) -> Vector3<f32> {
+    if direction.normalize().y.abs() > 0.99 { Vector3::unit_z() } else { Vector3::unit_y() }
+}
+
+// View-projection of one cascade: an orthographic box around the bounding
+// sphere of the slice, so it doesn't change size as the camera turns, moved
+// in whole texels to keep shadow edges from crawling. The box reaches
+// `caster_margin` further towards the light for casters outside the view.
+pub fn cascade_matrix(direction: Vector3<f32>, corners: &[Point3<f32>; 8], map_size: u32, caster_margin: f32) -> Matrix4<f32> {
+    let center = Point3::centroid(corners);
+    let radius = corners.iter().map(|c| c.distance(center)).fold(0.0, f32::max);
+    let radius = (radius * 16.0).ceil() / 16.0;
+    let view = Matrix4::look_to_rh(Point3::origin(), direction.normalize(), light_up(direction));
+    let center = view.transform_point(center);
+    let texel = 2.0 * radius / map_size as f32;
+    let (x, y) = ((center.x / texel).floor() * texel, (center.y / texel).floor() * texel);
+    // the light looks down -z
+    let projection = ortho(x - radius, x + radius, y - radius, y + radius, -center.z - radius - caster_margin, -center.z + radius);
+    transforms::OPENGL_TO_WGPU_MATRIX * projection * view
+}
+
+pub fn spot_matrix(position: Point3<f32>, direction: Vector3<f32>, range: f32, outer_angle: Rad<f32>) -> Matrix4<f32> {
+    let fovy = Rad((outer_angle.0 * 2.0).min(Rad::from(Deg(170.0)).0));
+    let view = Matrix4::look_to_rh(position, direction.normalize(), light_up(direction));
+    transforms::OPENGL_TO_WGPU_MATRIX * perspective(fovy, 1.0, SPOT_NEAR, range.max(SPOT_NEAR * 2.0)) * view
+}
+
+// The six 90 degree views of a point light, in CUBE_FACES order.
+pub fn cube_face_matrices(position: Point3<f32>, range: f32) -> [Matrix4<f32>; 6] {
+    let projection = transforms::OPENGL_TO_WGPU_MATRIX * perspective(Deg(90.0), 1.0, SPOT_NEAR, range.max(SPOT_NEAR * 2.0));
+    CUBE_FACES.map(|(forward, up)| projection * Matrix4::look_to_rh(position, forward.into(), up.into()))
+}
+
+// Depth maps rendered from the lights before the main pass, which
+// lightning.wgsl samples with PCF from bind group 2. Directional and spot
+// lights share one texture array. Point lights render six faces into a
+// second one, storing distance / range as depth so lookups can compare
+// distances.
+pub struct ShadowMaps {
+    settings: ShadowSettings,
+    texture: wgpu::Texture,
+    layer_views: Vec<wgpu::TextureView>,
+    point_texture: wgpu::Texture,
+    point_layer_views: Vec<wgpu::TextureView>,
+    sampler: wgpu::Sampler,
+    uniform_buffer: wgpu::Buffer,
+    bind_group_layout: wgpu::BindGroupLayout,
+    bind_group: wgpu::BindGroup,
+    // one light matrix per layer for the depth pass, `pass_stride` apart
+    // and picked with a dynamic offset
+    pass_buffer: wgpu::Buffer,
+    pass_stride: u32,
+    pass_bind_group: wgpu::BindGroup,
+    pass_layout: wgpu::PipelineLayout,
+    pipeline: wgpu::RenderPipeline,
+    point_pipeline: wgpu::RenderPipeline,
+    layers_used: u32,
+    point_layers_used: u32,
+}
+
+impl ShadowMaps {
+    pub fn new(device: &wgpu::Device, settings: ShadowSettings) -> Self {
+        let settings = ShadowSettings { cascades: settings.cascades.clamp(1, MAX_CASCADES), ..settings };
+        let (texture, layer_views) = create_maps(device, settings.map_size, MAX_SHADOW_MAPS);
+        let (point_texture, point_layer_views) = create_maps(device, settings.point_map_size, POINT_TEXTURE_LAYERS);
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            label: Some("shadow sampler"),
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            // linear comparisons give every PCF tap a 2x2 bilinear filter
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            compare: Some(wgpu::CompareFunction::LessEqual),
+            ..Default::default()
+        });
+        let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Shadow Uniform Buffer"),
+            size: std::mem::size_of::<ShadowUniform>() as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        sample_type: wgpu::TextureSampleType::Depth,
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler(wgpu::SamplerBindingType::Comparison),
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 3,
+                    visibility: wgpu::ShaderStages::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        sample_type: wgpu::TextureSampleType::Depth,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("shadow_bind_group_layout"),
+        });
+        let bind_group = create_bind_group(device, &bind_group_layout, &uniform_buffer, &texture, &point_texture, &sampler);
+
+        // point light faces come after the MAX_SHADOW_MAPS layers
+        let pass_size = std::mem::size_of::<ShadowPassUniform>() as u32;
+        let pass_stride = device.limits().min_uniform_buffer_offset_alignment.max(pass_size);
+        let pass_buffer = device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("Shadow Pass Buffer"),
+            size: pass_stride as wgpu::BufferAddress * (MAX_SHADOW_MAPS + POINT_LAYERS) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsages::UNIFORM | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let pass_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[wgpu::BindGroupLayoutEntry {
+                binding: 0,
+                visibility: wgpu::ShaderStages::VERTEX_FRAGMENT,
+                ty: wgpu::BindingType::Buffer {
+                    ty: wgpu::BufferBindingType::Uniform,
+                    has_dynamic_offset: true,
+                    min_binding_size: NonZeroU64::new(pass_size as u64),
+                },
+                count: None,
+            }],
+            label: Some("shadow_pass_bind_group_layout"),
+        });
+        let pass_bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &pass_bind_group_layout,
+            entries: &[wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::Buffer(wgpu::BufferBinding {
+                    buffer: &pass_buffer,
+                    offset: 0,
+                    size: NonZeroU64::new(pass_size as u64),
+                }),
+            }],
+            label: Some("shadow_pass_bind_group"),
+        });
+        let pass_layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            label: Some("shadow pipeline"),
+            bind_group_layouts: &[&pass_bind_group_layout],
+            push_constant_ranges: &[],
+        });
+        let pipeline = create_pipeline(device, &pass_layout, &settings);
+        let point_pipeline = create_point_pipeline(device, &pass_layout);
+        Self {
+            settings,
+            texture,
+            layer_views,
+            point_texture,
+            point_layer_views,
+            sampler,
+            uniform_buffer,
+            bind_group_layout,
+            bind_group,
+            pass_buffer,
+            pass_stride,
+            pass_bind_group,
+            pass_layout,
+            pipeline,
+            point_pipeline,
+            layers_used: 0,
+            point_layers_used: 0,
+        }
+    }
+
+    pub fn settings(&self) -> ShadowSettings {
+        self.settings
+    }
+
+    // Recreates the maps when their size changes and the pipeline when the
+    // depth bias does; takes effect with the next update.
+    pub fn set_settings(&mut self, device: &wgpu::Device, settings: ShadowSettings) {
+        let settings = ShadowSettings { cascades: settings.cascades.clamp(1, MAX_CASCADES), ..settings };
+        if (settings.map_size, settings.point_map_size) != (self.settings.map_size, self.settings.point_map_size) {
+            (self.texture, self.layer_views) = create_maps(device, settings.map_size, MAX_SHADOW_MAPS);
+            (self.point_texture, self.point_layer_views) = create_maps(device, settings.point_map_size, POINT_TEXTURE_LAYERS);
+            self.bind_group = create_bind_group(
+                device,
+                &self.bind_group_layout,
+                &self.uniform_buffer,
+                &self.texture,
+                &self.point_texture,
+                &self.sampler,
+            );
+        }
+        if (settings.depth_bias, settings.slope_bias) != (self.settings.depth_bias, self.settings.slope_bias) {
+            self.pipeline = create_pipeline(device, &self.pass_layout, &settings);
+        }
+        self.settings = settings;
+    }
+
+    // layout of bind group 2 in lightning.wgsl
+    pub fn bind_group_layout(&self) -> &wgpu::BindGroupLayout {
+        &self.bind_group_layout
+    }
+
+    pub fn bind_group(&self) -> &wgpu::BindGroup {
+        &self.bind_group
+    }
+
+    // Fits the light matrices to the current view and tells the lights in
+    // `uniform` which layers are theirs. `model_mat` is the matrix applied
+    // to all instances.
+    #[allow(clippy::too_many_arguments)]
+    pub fn update(
+        &mut self,
+        queue: &wgpu::Queue,
+        lights: &[lights::Light],
+        camera: &Camera,
+        projection: &transforms::Projection,
+        aspect: f32,
+        model_mat: Matrix4<f32>,
+        uniform: &mut lights::LightsUniform,
+    ) {
+        let settings = self.settings;
+        let near = match *projection {
+            transforms::Projection::Perspective { near, .. } | transforms::Projection::InfiniteReverseZ { near, .. } => near,
+            transforms::Projection::Orthographic { near, .. } => near.max(0.0),
+        };
+        let splits = cascade_splits(near.max(0.01), settings.distance, settings.cascades);
+        let mut shadow = ShadowUniform {
+            light_view_proj: [Matrix4::identity().into(); MAX_SHADOW_MAPS],
+            params: [settings.normal_bias, settings.pcf_radius as f32, 1.0 / settings.map_size as f32, settings.point_bias],
+            point_params: [1.0 / settings.point_map_size as f32, 0.0, 0.0, 0.0],
+        };
+        let lights = &lights[..lights.len().min(lights::MAX_LIGHTS)];
+        self.layers_used = 0;
+        self.point_layers_used = 0;
+        for (i, (light, layers)) in lights.iter().zip(assign_layers(lights, settings.cascades)).enumerate() {
+            let Some((first, count)) = layers else { continue };
+            uniform.lights[i].shadow_layer = first as i32;
+            uniform.lights[i].shadow_layers = count;
+            if let lights::LightKind::Point { position, range } = light.kind {
+                for (face, matrix) in cube_face_matrices(position, range).into_iter().enumerate() {
+                    let layer = first as usize + face;
+                    self.write_pass(queue, MAX_SHADOW_MAPS + layer, matrix, model_mat, [position.x, position.y, position.z, range]);
+                }
+                self.point_layers_used = first + count;
+                continue;
+            }
+            let matrices: Vec<Matrix4<f32>> = match light.kind {
+                lights::LightKind::Directional { direction } => splits
+                    .windows(2)
+                    .map(|split| {
+                        let corners = frustum_slice(camera, projection, aspect, split[0], split[1]);
+                        cascade_matrix(direction, &corners, settings.map_size, settings.distance)
+                    })
+                    .collect(),
+                lights::LightKind::Spot { position, direction, range, outer_angle, .. } => {
+                    vec![spot_matrix(position, direction, range, outer_angle)]
+                }
+                lights::LightKind::Point { .. } => unreachable!("point lights get no layers"),
+            };
+            for (layer, matrix) in (first..first + count).zip(matrices) {
+                shadow.light_view_proj[layer as usize] = matrix.into();
+                self.write_pass(queue, layer as usize, matrix, model_mat, [0.0; 4]);
+            }
+            self.layers_used = first + count;
+        }
+        queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[shadow]));
+    }
+
+    fn write_pass(&self, queue: &wgpu::Queue, slot: usize, matrix: Matrix4<f32>, model_mat: Matrix4<f32>, light: [f32; 4]) {
+        let pass = ShadowPassUniform {
+            light_model_mat: (matrix * model_mat).into(),
+            model_mat: model_mat.into(),
+            light,
+        };
+        let offset = slot as wgpu::BufferAddress * self.pass_stride as wgpu::BufferAddress;
+        queue.write_buffer(&self.pass_buffer, offset, bytemuck::cast_slice(&[pass]));
+    }
+
+    // Draws the model's instances into every layer a light was given in the
+    // last update.
+    pub fn render(&self, encoder: &mut wgpu::CommandEncoder, model: &model::Model, instances: &instancing::InstanceSet) {
+        let layers = (0..self.layers_used as usize).map(|layer| (&self.layer_views[layer], &self.pipeline, layer));
+        let point_layers = (0..self.point_layers_used as usize)
+            .map(|layer| (&self.point_layer_views[layer], &self.point_pipeline, MAX_SHADOW_MAPS + layer));
+        for (view, pipeline, slot) in layers.chain(point_layers) {
+            let mut shadow_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Shadow Pass"),
+                color_attachments: &[],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(SHADOW_DEPTH.clear_value()),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+            let Some(instance_buffer) = instances.buffer().filter(|_| !instances.is_empty()) else { continue };
+            shadow_pass.set_pipeline(pipeline);
+            shadow_pass.set_bind_group(0, &self.pass_bind_group, &[slot as u32 * self.pass_stride]);
+            shadow_pass.set_vertex_buffer(1, instance_buffer.slice(..));
+            shadow_pass.draw_model_depth(model, instances);
+        }
+    }
+}
+
+fn create_maps(device: &wgpu::Device, map_size: u32, layers: usize) -> (wgpu::Texture, Vec<wgpu::TextureView>) {
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("shadow maps"),
+        size: wgpu::Extent3d {
+            width: map_size,
+            height: map_size,
+            depth_or_array_layers: layers as u32,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: SHADOW_DEPTH.format(),
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::TEXTURE_BINDING,
+        view_formats: &[],
+    });
+    // the depth pass renders into one layer at a time
+    let layer_views = (0..layers as u32)
+        .map(|layer| {
+            texture.create_view(&wgpu::TextureViewDescriptor {
+                label: Some("shadow map layer"),
+                dimension: Some(wgpu::TextureViewDimension::D2),
+                base_array_layer: layer,
+                array_layer_count: Some(1),
+                ..Default::default()
+            })
+        })
+        .collect();
+    (texture, layer_views)
+}
+
+fn create_bind_group(
+    device: &wgpu::Device,
+    layout: &wgpu::BindGroupLayout,
+    uniform_buffer: &wgpu::Buffer,
+    texture: &wgpu::Texture,
+    point_texture: &wgpu::Texture,
+    sampler: &wgpu::Sampler,
+) -> wgpu::BindGroup {
+    let array_view = |texture: &wgpu::Texture| {
+        texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        })
+    };
+    let (view, point_view) = (array_view(texture), array_view(point_texture));
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: uniform_buffer.as_entire_binding(),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::TextureView(&view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 2,
+                resource: wgpu::BindingResource::Sampler(sampler),
+            },
+            wgpu::BindGroupEntry {
+                binding: 3,
+                resource: wgpu::BindingResource::TextureView(&point_view),
+            },
+        ],
+        label: Some("shadow_bind_group"),
+    })
+}
+
+fn create_pipeline(device: &wgpu::Device, layout: &wgpu::PipelineLayout, settings: &ShadowSettings) -> wgpu::RenderPipeline {
+    pipeline::PipelineBuilder::new(
+        wgpu::ShaderModuleDescriptor {
+            label: Some("Shadow Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shadow.wgsl").into()),
+        },
+        // unused without a color target
+        wgpu::TextureFormat::Rgba8Unorm,
+    )
+    .label("Shadow Pipeline")
+    .layout(layout)
+    .vertex_layouts(&[model::ModelVertex::desc(), instancing::InstanceRaw::desc()])
+    .depth_only()
+    // thin and double-sided geometry has to cast shadows from both sides
+    .cull_mode(None)
+    .depth(SHADOW_DEPTH)
+    .depth_bias(settings.depth_bias, settings.slope_bias, 0.0)
+    .build(device)
+}
+
+fn create_point_pipeline(device: &wgpu::Device, layout: &wgpu::PipelineLayout) -> wgpu::RenderPipeline {
+    pipeline::PipelineBuilder::new(
+        wgpu::ShaderModuleDescriptor {
+            label: Some("Point Shadow Shader"),
+            source: wgpu::ShaderSource::Wgsl(include_str!("shadow.wgsl").into()),
+        },
+        wgpu::TextureFormat::Rgba8Unorm,
+    )
+    .label("Point Shadow Pipeline")
+    .layout(layout)
+    .vertex_layouts(&[model::ModelVertex::desc(), instancing::InstanceRaw::desc()])
+    .entry_points("vs_point", "fs_point")
+    .depth_only_fragment()
+    .cull_mode(None)
+    .depth(SHADOW_DEPTH)
+    .build(device)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn to_clip(matrix: Matrix4<f32>, point: Point3<f32>) -> Vector3<f32> {
+        let clip = matrix * point.to_homogeneous();
+        clip.truncate() / clip.w
+    }
+
+    fn inside(ndc: Vector3<f32>) -> bool {
+        ndc.x.abs() <= 1.0 + 1e-4 && ndc.y.abs() <= 1.0 + 1e-4 && (-1e-4..=1.0 + 1e-4).contains(&ndc.z)
+    }
+
+    #[test]
+    fn splits_cover_near_to_far() {
+        let splits = cascade_splits(0.1, 60.0, 3);
+        assert_eq!(splits.len(), 4);
+        assert!((splits[0] - 0.1).abs() < 1e-5 && (splits[3] - 60.0).abs() < 1e-3);
+        assert!(splits.windows(2).all(|w| w[0] < w[1]));
+        // the near cascade is the smallest
+        assert!(splits[1] - splits[0] < splits[2] - splits[1]);
+    }
+
+    #[test]
+    fn cascades_contain_their_slice() {
+        let camera = Camera { position: (0.0, 5.0, -10.0).into(), direction: Point3::origin(), up: Vector3::unit_y() };
+        let projection = transforms::Projection::default();
+        let direction = Vector3::new(-0.4, -1.0, 0.3);
+        let splits = cascade_splits(0.1, 40.0, 3);
+        for split in splits.windows(2) {
+            let corners = frustum_slice(&camera, &projection, 4.0 / 3.0, split[0], split[1]);
+            let matrix = cascade_matrix(direction, &corners, 1024, 40.0);
+            assert!(corners.iter().all(|&c| inside(to_clip(matrix, c))));
+            // casters between the light and the slice land in the map too
+            let caster = corners[0] - direction.normalize() * 20.0;
+            assert!(inside(to_clip(matrix, caster)));
+        }
+    }
+
+    #[test]
+    fn spot_matrix_covers_the_cone() {
+        let matrix = spot_matrix((0.0, 4.0, 0.0).into(), (0.0, -1.0, 0.0).into(), 10.0, Deg(30.0).into());
+        assert!(inside(to_clip(matrix, (0.0, 0.0, 0.0).into())));
+        assert!(inside(to_clip(matrix, (1.5, 0.0, 0.0).into())));
+        // past the range
+        assert!(!inside(to_clip(matrix, (0.0, -7.0, 0.0).into())));
+    }
+
+    #[test]
+    fn layers_are_handed_out_until_they_run_out() {
+        let sun = lights::Light::directional((0.0, -1.0, 0.0).into(), [1.0; 3], 1.0);
+        let spot = lights::Light::spot(Point3::origin(), -Vector3::unit_y(), 5.0, Rad(0.2), Rad(0.4), [1.0; 3], 1.0);
+        let point = lights::Light::point(Point3::origin(), 5.0, [1.0; 3], 1.0);
+        let mut unlit = spot;
+        unlit.cast_shadows = false;
+        let layers = assign_layers(&[sun, point, spot, unlit, sun, spot], 3);
+        assert_eq!(layers, vec![Some((0, 3)), Some((0, 6)), Some((3, 1)), None, Some((4, 3)), Some((7, 1))]);
+        assert_eq!(assign_layers(&[sun, sun, sun], 4), vec![Some((0, 4)), Some((4, 4)), None]);
+        // point lights count in their own array
+        let points = assign_layers(&[point, sun, point, point], 4);
+        assert_eq!(points, vec![Some((0, 6)), Some((0, 4)), Some((6, 6)), None]);
+    }
+
+    #[test]
+    fn cube_faces_look_along_the_axes() {
+        let matrices = cube_face_matrices((1.0, 2.0, 3.0).into(), 10.0);
+        for ((forward, _), matrix) in CUBE_FACES.iter().zip(matrices) {
+            let ahead = Point3::new(1.0, 2.0, 3.0) + Vector3::from(*forward) * 5.0;
+            let ndc = to_clip(matrix, ahead);
+            assert!(ndc.x.abs() < 1e-5 && ndc.y.abs() < 1e-5 && inside(ndc));
+            assert!(!inside(to_clip(matrix, Point3::new(1.0, 2.0, 3.0) - Vector3::from(*forward) * 5.0)));
+        }
+    }
+}
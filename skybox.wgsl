@@ -0,0 +1,33 @@
+// Draws the environment cubemap behind the scene. It runs first in the main
+// pass without writing or testing depth, so the scene simply draws over it.
+struct Sky {
+    // inverse of projection * view without the camera translation
+    inv_view_proj: mat4x4<f32>,
+};
+
+@binding(3) @group(0) var s_environment: sampler;
+@binding(4) @group(0) var t_environment: texture_cube<f32>;
+@binding(5) @group(0) var<uniform> sky: Sky;
+
+struct Output {
+    @builtin(position) position: vec4<f32>,
+    @location(0) ndc: vec2<f32>,
+};
+
+@vertex
+fn vs_main(@builtin(vertex_index) index: u32) -> Output {
+    // one triangle covering the screen: (-1, -1), (3, -1), (-1, 3)
+    let x = f32((index & 1u) << 2u) - 1.0;
+    let y = f32((index & 2u) << 1u) - 1.0;
+    var output: Output;
+    output.position = vec4<f32>(x, y, 0.5, 1.0);
+    output.ndc = vec2<f32>(x, y);
+    return output;
+}
+
+@fragment
+fn fs_main(in: Output) -> @location(0) vec4<f32> {
+    let p = sky.inv_view_proj * vec4<f32>(in.ndc, 0.5, 1.0);
+    let dir = p.xyz / p.w;
+    return vec4<f32>(textureSampleLevel(t_environment, s_environment, dir, 0.0).rgb, 1.0);
+}
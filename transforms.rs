@@ -11,43 +11,279 @@ pub const OPENGL_TO_WGPU_MATRIX: Matrix4<f32> = Matrix4::new(
     0.0, 0.0, 0.5, 0.0,
     0.0, 0.0, 0.5, 1.0,
 );
+// Turns clip depth z into w - z, which flips the 0..1 depth range of a
+// finite projection for DepthMode::ReverseZ.
+#[rustfmt::skip]
+pub const REVERSE_DEPTH_MATRIX: Matrix4<f32> = Matrix4::new(
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, -1.0, 0.0,
+    0.0, 0.0, 1.0, 1.0,
+);
+
 pub struct InitWgpu {
-    pub surface: wgpu::Surface,
+    pub surface: Option<wgpu::Surface>,
+    pub adapter: wgpu::Adapter,
     pub device: wgpu::Device,
     pub queue: wgpu::Queue,
     pub config: wgpu::SurfaceConfiguration,
     pub size: winit::dpi::PhysicalSize<u32>,
+    // color target used instead of the swapchain when running headless
+    pub offscreen: Option<wgpu::Texture>,
+}
+
+// The color target of one frame: either a swapchain image that has to be
+// presented, or a view into the offscreen texture.
+pub struct Frame {
+    pub output: Option<wgpu::SurfaceTexture>,
+    pub view: wgpu::TextureView,
+}
+
+impl Frame {
+    pub fn present(self) {
+        if let Some(output) = self.output {
+            output.present();
+        }
+    }
+}
+
+// Adapter and device selection for InitWgpu. Defaults match the window
+// path (Vulkan, default power preference, hardware adapter); every field can
+// be overridden from the environment or the command line.
+#[derive(Clone, Debug)]
+pub struct InitConfig {
+    pub backends: wgpu::Backends,
+    pub power_preference: wgpu::PowerPreference,
+    pub force_fallback_adapter: bool,
+    pub features: wgpu::Features,
+    pub limits: wgpu::Limits,
+}
+
+impl Default for InitConfig {
+    fn default() -> Self {
+        Self {
+            backends: wgpu::Backends::VULKAN,
+            power_preference: wgpu::PowerPreference::default(),
+            force_fallback_adapter: false,
+            features: wgpu::Features::empty(),
+            limits: wgpu::Limits::default(),
+        }
+    }
+}
+
+impl InitConfig {
+    // software adapter on whatever backend provides one
+    pub fn headless() -> Self {
+        Self {
+            backends: wgpu::Backends::all(),
+            force_fallback_adapter: true,
+            limits: wgpu::Limits::downlevel_defaults(),
+            ..Default::default()
+        }
+    }
+
+    // WGPU_BACKEND, WGPU_POWER_PREF, WGPU_FALLBACK_ADAPTER, WGPU_FEATURES, WGPU_LIMITS
+    pub fn with_env(mut self) -> Result<Self, InitError> {
+        for (var, option) in [
+            ("WGPU_BACKEND", "backend"),
+            ("WGPU_POWER_PREF", "power"),
+            ("WGPU_FALLBACK_ADAPTER", "fallback-adapter"),
+            ("WGPU_FEATURES", "features"),
+            ("WGPU_LIMITS", "limits"),
+        ] {
+            if let Ok(value) = std::env::var(var) {
+                self.set(option, &value)?;
+            }
+        }
+        Ok(self)
+    }
+
+    // --backend=gl,vulkan --power=high --fallback-adapter --features=A,B --limits=downlevel
+    // Arguments that aren't init options are left for the caller.
+    pub fn with_args<I: IntoIterator<Item = String>>(mut self, args: I) -> Result<Self, InitError> {
+        for arg in args {
+            let Some(arg) = arg.strip_prefix("--") else { continue };
+            let (option, value) = arg.split_once('=').unwrap_or((arg, "true"));
+            if Self::OPTIONS.contains(&option) {
+                self.set(option, value)?;
+            }
+        }
+        Ok(self)
+    }
+
+    const OPTIONS: [&'static str; 5] = ["backend", "power", "fallback-adapter", "features", "limits"];
+
+    fn set(&mut self, option: &str, value: &str) -> Result<(), InitError> {
+        let invalid = || InitError::InvalidOption {
+            option: option.to_string(),
+            value: value.to_string(),
+        };
+        match option {
+            "backend" => {
+                self.backends = match value.to_lowercase().as_str() {
+                    "all" => wgpu::Backends::all(),
+                    "primary" => wgpu::Backends::PRIMARY,
+                    list => {
+                        let mut backends = wgpu::Backends::empty();
+                        for name in list.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+                            backends |= match name {
+                                "vulkan" | "vk" => wgpu::Backends::VULKAN,
+                                "dx12" | "d3d12" => wgpu::Backends::DX12,
+                                "dx11" | "d3d11" => wgpu::Backends::DX11,
+                                "metal" | "mtl" => wgpu::Backends::METAL,
+                                "opengl" | "gles" | "gl" => wgpu::Backends::GL,
+                                "webgpu" => wgpu::Backends::BROWSER_WEBGPU,
+                                _ => return Err(invalid()),
+                            };
+                        }
+                        backends
+                    }
+                };
+                if self.backends.is_empty() {
+                    return Err(invalid());
+                }
+            }
+            "power" => {
+                self.power_preference = match value.to_lowercase().as_str() {
+                    "low" => wgpu::PowerPreference::LowPower,
+                    "high" => wgpu::PowerPreference::HighPerformance,
+                    _ => return Err(invalid()),
+                };
+            }
+            "fallback-adapter" => {
+                self.force_fallback_adapter = match value {
+                    "1" | "true" => true,
+                    "0" | "false" => false,
+                    _ => return Err(invalid()),
+                };
+            }
+            "features" => {
+                for name in value.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+                    self.features |= wgpu::Features::from_name(&name.to_uppercase()).ok_or_else(invalid)?;
+                }
+            }
+            "limits" => {
+                self.limits = match value.to_lowercase().as_str() {
+                    "default" => wgpu::Limits::default(),
+                    "downlevel" => wgpu::Limits::downlevel_defaults(),
+                    "webgl2" => wgpu::Limits::downlevel_webgl2_defaults(),
+                    _ => return Err(invalid()),
+                };
+            }
+            _ => return Err(invalid()),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum InitError {
+    InvalidOption { option: String, value: String },
+    CreateSurface(wgpu::CreateSurfaceError),
+    NoAdapter {
+        backends: wgpu::Backends,
+        power_preference: wgpu::PowerPreference,
+        force_fallback_adapter: bool,
+        available: Vec<wgpu::AdapterInfo>,
+    },
+    MissingFeatures { adapter: wgpu::AdapterInfo, missing: wgpu::Features },
+    RequestDevice(wgpu::RequestDeviceError),
+}
+
+impl std::fmt::Display for InitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InitError::InvalidOption { option, value } => write!(f, "invalid value {:?} for {}", value, option),
+            InitError::CreateSurface(e) => write!(f, "failed to create surface: {}", e),
+            InitError::NoAdapter { backends, power_preference, force_fallback_adapter, available } => {
+                write!(
+                    f,
+                    "no adapter for backends {:?} (power preference {:?}, fallback adapter {})",
+                    backends, power_preference, force_fallback_adapter
+                )?;
+                if available.is_empty() {
+                    write!(f, "; no adapters found on any backend")
+                } else {
+                    write!(f, "; adapters found:")?;
+                    for info in available {
+                        write!(f, "\n  {} ({:?}, {:?})", info.name, info.backend, info.device_type)?;
+                    }
+                    Ok(())
+                }
+            }
+            InitError::MissingFeatures { adapter, missing } => {
+                write!(f, "adapter {} does not support features {:?}", adapter.name, missing)
+            }
+            InitError::RequestDevice(e) => write!(f, "failed to request device: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for InitError {}
+
+async fn request_device(
+    instance: &wgpu::Instance,
+    surface: Option<&wgpu::Surface>,
+    config: &InitConfig,
+) -> Result<(wgpu::Adapter, wgpu::Device, wgpu::Queue), InitError> {
+    let adapter = instance
+        .request_adapter(&wgpu::RequestAdapterOptions {
+            power_preference: config.power_preference,
+            compatible_surface: surface,
+            force_fallback_adapter: config.force_fallback_adapter,
+        })
+        .await
+        .ok_or_else(|| InitError::NoAdapter {
+            backends: config.backends,
+            power_preference: config.power_preference,
+            force_fallback_adapter: config.force_fallback_adapter,
+            available: available_adapters(),
+        })?;
+
+    let missing = config.features - adapter.features();
+    if !missing.is_empty() {
+        return Err(InitError::MissingFeatures { adapter: adapter.get_info(), missing });
+    }
+
+    let (device, queue) = adapter
+        .request_device(
+            &wgpu::DeviceDescriptor {
+                label: None,
+                features: config.features,
+                limits: config.limits.clone(),
+            },
+            None, // Trace path
+        )
+        .await
+        .map_err(InitError::RequestDevice)?;
+    Ok((adapter, device, queue))
+}
+
+// Every adapter on every backend, not only the ones the config allowed, so the
+// error can point at a backend that would have worked.
+#[cfg(not(target_arch = "wasm32"))]
+fn available_adapters() -> Vec<wgpu::AdapterInfo> {
+    wgpu::Instance::new(wgpu::InstanceDescriptor::default())
+        .enumerate_adapters(wgpu::Backends::all())
+        .map(|adapter| adapter.get_info())
+        .collect()
+}
+
+#[cfg(target_arch = "wasm32")]
+fn available_adapters() -> Vec<wgpu::AdapterInfo> {
+    Vec::new()
 }
 
 impl InitWgpu {
-    pub async fn init_wgpu(window: &Window) -> Self {
+    pub async fn init_wgpu(window: &Window, init_config: &InitConfig) -> Result<Self, InitError> {
         let size = window.inner_size();
         //let instance = wgpu::Instance::new(wgpu::Backends::all());
         let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
-            backends: wgpu::Backends::VULKAN,
+            backends: init_config.backends,
             ..Default::default()
         });
-        let surface = unsafe { instance.create_surface(window).unwrap() };
-        let adapter = instance
-            .request_adapter(&wgpu::RequestAdapterOptions {
-                power_preference: wgpu::PowerPreference::default(),
-                compatible_surface: Some(&surface),
-                force_fallback_adapter:false,
-            })
-            .await
-            .unwrap();
-
-        let (device, queue) = adapter
-            .request_device(
-                &wgpu::DeviceDescriptor {
-                    label: None,
-                    features: wgpu::Features::empty(),
-                    limits: wgpu::Limits::default(),
-                },
-                None, // Trace path
-            )
-            .await
-            .unwrap();
+        let surface = unsafe { instance.create_surface(window) }.map_err(InitError::CreateSurface)?;
+        let (adapter, device, queue) = request_device(&instance, Some(&surface), init_config).await?;
         let surface_cap = surface.get_capabilities(&adapter);
         let surface_format = surface_cap.formats
             .iter()
@@ -65,14 +301,112 @@ impl InitWgpu {
         };
         surface.configure(&device, &config);
      
-        Self{
-            surface,
+        Ok(Self{
+            surface: Some(surface),
+            adapter,
+            device,
+            queue,
+            config,
+            size,
+            offscreen: None,
+        })
+    }
+
+    // Renders into an offscreen texture instead of a window surface, so frames
+    // can be drawn on machines without a GPU (see InitConfig::headless).
+    pub async fn init_headless(width: u32, height: u32, init_config: &InitConfig) -> Result<Self, InitError> {
+        let size = winit::dpi::PhysicalSize::new(width, height);
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor {
+            backends: init_config.backends,
+            ..Default::default()
+        });
+        let (adapter, device, queue) = request_device(&instance, None, init_config).await?;
+        let config = wgpu::SurfaceConfiguration {
+            usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            width,
+            height,
+            present_mode: wgpu::PresentMode::Fifo,
+            alpha_mode: wgpu::CompositeAlphaMode::Opaque,
+            view_formats: vec![]
+        };
+        let offscreen = create_offscreen_texture(&device, &config);
+
+        Ok(Self{
+            surface: None,
+            adapter,
             device,
             queue,
             config,
             size,
+            offscreen: Some(offscreen),
+        })
+    }
+
+    pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
+        self.size = new_size;
+        self.config.width = new_size.width;
+        self.config.height = new_size.height;
+        match &self.surface {
+            Some(surface) => surface.configure(&self.device, &self.config),
+            None => self.offscreen = Some(create_offscreen_texture(&self.device, &self.config)),
         }
     }
+
+    // MSAA sample counts usable with the surface format and `depth_format`.
+    // Without TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES only the counts every
+    // adapter guarantees are allowed, whatever this adapter could do.
+    pub fn supported_sample_counts(&self, depth_format: wgpu::TextureFormat) -> Vec<u32> {
+        let features = |format: wgpu::TextureFormat| {
+            if self.device.features().contains(wgpu::Features::TEXTURE_ADAPTER_SPECIFIC_FORMAT_FEATURES) {
+                self.adapter.get_texture_format_features(format).flags
+            } else {
+                format.guaranteed_format_features(self.device.features()).flags
+            }
+        };
+        let (color, depth) = (features(self.config.format), features(depth_format));
+        [1, 2, 4, 8]
+            .into_iter()
+            .filter(|&count| {
+                count == 1
+                    || (color.sample_count_supported(count)
+                        && color.contains(wgpu::TextureFormatFeatureFlags::MULTISAMPLE_RESOLVE)
+                        && depth.sample_count_supported(count))
+            })
+            .collect()
+    }
+
+    pub fn current_frame(&self) -> Result<Frame, wgpu::SurfaceError> {
+        match (&self.surface, &self.offscreen) {
+            (Some(surface), _) => {
+                let output = surface.get_current_texture()?;
+                let view = output.texture.create_view(&wgpu::TextureViewDescriptor::default());
+                Ok(Frame { output: Some(output), view })
+            }
+            (None, Some(texture)) => Ok(Frame {
+                output: None,
+                view: texture.create_view(&wgpu::TextureViewDescriptor::default()),
+            }),
+            (None, None) => Err(wgpu::SurfaceError::Lost),
+        }
+    }
+}
+
+pub fn create_offscreen_texture(device: &wgpu::Device, config: &wgpu::SurfaceConfiguration) -> wgpu::Texture {
+    device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("Offscreen Texture"),
+        size: wgpu::Extent3d {
+            width: config.width,
+            height: config.height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count: 1,
+        dimension: wgpu::TextureDimension::D2,
+        format: config.format,
+        usage: wgpu::TextureUsages::RENDER_ATTACHMENT | wgpu::TextureUsages::COPY_SRC,
+        view_formats: &[],
+    })
 }
 
 pub fn create_view(camera_position: Point3<f32>, look_direction: Point3<f32>, up_direction: Vector3<f32>) -> Matrix4<f32> {
@@ -134,6 +468,109 @@ pub fn create_view_projection(camera_position: Point3<f32>, look_direction: Poin
     (view_mat, project_mat, view_project_mat)
 } 
 
+// How the depth buffer is laid out. ReverseZ stores 1 at the near plane and
+// 0 at infinity in a float buffer, which spreads precision evenly over
+// distance, and has to be paired with Projection::InfiniteReverseZ.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, Default)]
+pub enum DepthMode {
+    #[default]
+    Standard,
+    ReverseZ,
+}
+
+impl DepthMode {
+    pub fn format(self) -> wgpu::TextureFormat {
+        match self {
+            DepthMode::Standard => wgpu::TextureFormat::Depth24Plus,
+            DepthMode::ReverseZ => wgpu::TextureFormat::Depth32Float,
+        }
+    }
+
+    pub fn compare(self) -> wgpu::CompareFunction {
+        match self {
+            DepthMode::Standard => wgpu::CompareFunction::Less,
+            DepthMode::ReverseZ => wgpu::CompareFunction::GreaterEqual,
+        }
+    }
+
+    pub fn clear_value(self) -> f32 {
+        match self {
+            DepthMode::Standard => 1.0,
+            DepthMode::ReverseZ => 0.0,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Projection {
+    Perspective { fovy: Rad<f32>, near: f32, far: f32 },
+    // Shows `height / zoom` world units vertically, the width follows the
+    // aspect ratio.
+    Orthographic { height: f32, zoom: f32, near: f32, far: f32 },
+    // Depth 1 at `near` falling towards 0 at infinity. Needs a depth buffer
+    // cleared to 0 and a GreaterEqual depth test.
+    InfiniteReverseZ { fovy: Rad<f32>, near: f32 },
+}
+
+impl Default for Projection {
+    fn default() -> Self {
+        Projection::Perspective { fovy: Rad(2.0*PI/5.0), near: 0.1, far: 100.0 }
+    }
+}
+
+impl Projection {
+    pub fn matrix(&self, aspect: f32) -> Matrix4<f32> {
+        match *self {
+            Projection::Perspective { fovy, near, far } => create_perspective_projection(fovy, aspect, near, far),
+            Projection::Orthographic { height, zoom, near, far } => {
+                let top = height / zoom * 0.5;
+                let right = top * aspect;
+                create_projection_ortho(-right, right, -top, top, near, far)
+            }
+            Projection::InfiniteReverseZ { fovy, near } => {
+                // already in wgpu's 0..1 depth range, z_clip = near and w_clip = -z_view
+                let f = 1.0 / (fovy / 2.0).tan();
+                #[rustfmt::skip]
+                let m = Matrix4::new(
+                    f / aspect, 0.0, 0.0, 0.0,
+                    0.0, f, 0.0, 0.0,
+                    0.0, 0.0, 0.0, -1.0,
+                    0.0, 0.0, near, 0.0,
+                );
+                m
+            }
+        }
+    }
+
+    pub fn fovy(&self) -> Option<Rad<f32>> {
+        match *self {
+            Projection::Perspective { fovy, .. } | Projection::InfiniteReverseZ { fovy, .. } => Some(fovy),
+            Projection::Orthographic { .. } => None,
+        }
+    }
+
+    // Orthographic views with `zoom` multiplied by `factor`; the others don't
+    // zoom.
+    pub fn zoomed(&self, factor: f32) -> Self {
+        match *self {
+            Projection::Orthographic { height, zoom, near, far } => {
+                Projection::Orthographic { height, zoom: zoom * factor, near, far }
+            }
+            projection => projection,
+        }
+    }
+
+    pub fn is_reverse_z(&self) -> bool {
+        matches!(self, Projection::InfiniteReverseZ { .. })
+    }
+
+    // An orthographic projection that shows what a perspective with `fovy`
+    // shows at `distance` from the camera, with the same near and far.
+    pub fn orthographic_from(fovy: Rad<f32>, distance: f32, near: f32, far: f32) -> Self {
+        Projection::Orthographic { height: 2.0 * (fovy / 2.0).tan(), zoom: 1.0 / distance, near, far }
+    }
+}
+
 pub fn create_transforms(translation:[f32; 3], rotation:[f32; 3], scaling:[f32; 3]) -> Matrix4<f32> {
 
     // create transformation matrices
@@ -153,4 +590,97 @@ pub fn create_transforms(translation:[f32; 3], rotation:[f32; 3], scaling:[f32;
 pub fn rotation_mat(rotation: [f32; 3]) -> cgmath::Quaternion<f32> {
 
     cgmath::Quaternion::from_sv(1.0, rotation.into())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // depth in 0..1 of a point on the view axis, after the perspective divide
+    fn depth(projection: &Projection, z_view: f32) -> f32 {
+        let clip = projection.matrix(1.5) * Vector4::new(0.0, 0.0, z_view, 1.0);
+        clip.z / clip.w
+    }
+
+    #[test]
+    fn perspective_maps_near_and_far_to_zero_and_one() {
+        let projection = Projection::Perspective { fovy: Rad(1.0), near: 0.5, far: 50.0 };
+        assert!(depth(&projection, -0.5).abs() < 1e-5);
+        assert!((depth(&projection, -50.0) - 1.0).abs() < 1e-5);
+        assert!(depth(&projection, -5.0) > 0.0 && depth(&projection, -5.0) < 1.0);
+    }
+
+    #[test]
+    fn orthographic_maps_near_and_far_to_zero_and_one() {
+        let projection = Projection::Orthographic { height: 6.0, zoom: 1.0, near: -1.0, far: 6.0 };
+        assert!(depth(&projection, 1.0).abs() < 1e-5);
+        assert!((depth(&projection, -6.0) - 1.0).abs() < 1e-5);
+        // same as the old hardcoded ortho(-4, 4, -3, 3, -1, 6) box at 4:3
+        let old = OPENGL_TO_WGPU_MATRIX * ortho(-4.0, 4.0, -3.0, 3.0, -1.0, 6.0);
+        assert!(projection.matrix(4.0 / 3.0).abs_diff_eq(&old, 1e-5));
+    }
+
+    #[test]
+    fn orthographic_extents_follow_aspect_and_zoom() {
+        let projection = Projection::Orthographic { height: 4.0, zoom: 2.0, near: 0.1, far: 100.0 };
+        // 4 units at zoom 2 shows y in -1..1 and x in -2..2 at 2:1
+        let corner = projection.matrix(2.0) * Vector4::new(2.0, 1.0, -1.0, 1.0);
+        assert!((corner.x - 1.0).abs() < 1e-5 && (corner.y - 1.0).abs() < 1e-5);
+
+        let matched = Projection::orthographic_from(Rad(std::f32::consts::FRAC_PI_2), 10.0, 0.1, 100.0);
+        let edge = matched.matrix(1.0) * Vector4::new(0.0, 10.0, -1.0, 1.0);
+        assert!((edge.y - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn infinite_reverse_z_puts_near_at_one_and_infinity_at_zero() {
+        let projection = Projection::InfiniteReverseZ { fovy: Rad(1.0), near: 0.1 };
+        assert!((depth(&projection, -0.1) - 1.0).abs() < 1e-6);
+        assert!(depth(&projection, -1.0e7) < 1e-7);
+        assert!(depth(&projection, -1.0) > depth(&projection, -10.0));
+        assert!(projection.is_reverse_z());
+    }
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn init_args_override_defaults() {
+        let config = InitConfig::default()
+            .with_args(args(&["--backend=gl,Vulkan", "--power=high", "--fallback-adapter", "--limits=downlevel", "--other=1"]))
+            .unwrap();
+        assert_eq!(config.backends, wgpu::Backends::GL | wgpu::Backends::VULKAN);
+        assert_eq!(config.power_preference, wgpu::PowerPreference::HighPerformance);
+        assert!(config.force_fallback_adapter);
+        assert_eq!(config.limits.max_texture_dimension_2d, wgpu::Limits::downlevel_defaults().max_texture_dimension_2d);
+
+        let mut config = InitConfig::default();
+        config.set("features", "depth_clip_control, polygon_mode_line").unwrap();
+        assert_eq!(config.features, wgpu::Features::DEPTH_CLIP_CONTROL | wgpu::Features::POLYGON_MODE_LINE);
+        config.set("fallback-adapter", "0").unwrap();
+        assert!(!config.force_fallback_adapter);
+    }
+
+    #[test]
+    fn invalid_init_values_are_rejected() {
+        for (option, value) in [
+            ("backend", "gl,foo"),
+            ("backend", ""),
+            ("power", "medium"),
+            ("fallback-adapter", "maybe"),
+            ("features", "not_a_feature"),
+            ("limits", "huge"),
+            ("unknown", "1"),
+        ] {
+            let result = InitConfig::default().set(option, value);
+            assert!(
+                matches!(&result, Err(InitError::InvalidOption { option: o, value: v }) if o == option && v == value),
+                "{}={} was accepted",
+                option,
+                value
+            );
+        }
+        assert!(InitConfig::default().with_args(args(&["--backend=gl,foo"])).is_err());
+    }
+}